@@ -27,6 +27,42 @@ Register-ArgumentCompleter -Native -CommandName 'tlslb' -ScriptBlock {
             [CompletionResult]::new('--help', '--help', [CompletionResultType]::ParameterName, 'Print help')
             [CompletionResult]::new('-V', '-V ', [CompletionResultType]::ParameterName, 'Print version')
             [CompletionResult]::new('--version', '--version', [CompletionResultType]::ParameterName, 'Print version')
+            [CompletionResult]::new('route-test', 'route-test', [CompletionResultType]::ParameterValue, 'Show which route a client hello would hit')
+            [CompletionResult]::new('ech-config', 'ech-config', [CompletionResultType]::ParameterValue, 'Print the `ECHConfigList` of a frontend for the `ech` parameter of DNS HTTPS records')
+            [CompletionResult]::new('help', 'help', [CompletionResultType]::ParameterValue, 'Print this message or the help of the given subcommand(s)')
+            break
+        }
+        'tlslb;route-test' {
+            [CompletionResult]::new('--client-hello', '--client-hello', [CompletionResultType]::ParameterName, 'File containing a TLS client hello record, either raw or as hex')
+            [CompletionResult]::new('--sni', '--sni', [CompletionResultType]::ParameterName, 'SNI of the client, if no client hello is given')
+            [CompletionResult]::new('--alpn', '--alpn', [CompletionResultType]::ParameterName, 'Offered ALPN protocol, can be repeated, if no client hello is given')
+            [CompletionResult]::new('--tls-version', '--tls-version', [CompletionResultType]::ParameterName, 'Highest TLS version of the client like `1.3`, if no client hello is given')
+            [CompletionResult]::new('--ja4', '--ja4', [CompletionResultType]::ParameterName, 'JA4 fingerprint of the client, if no client hello is given')
+            [CompletionResult]::new('--client-ip', '--client-ip', [CompletionResultType]::ParameterName, 'Address of the client')
+            [CompletionResult]::new('--frontend', '--frontend', [CompletionResultType]::ParameterName, 'Name of the frontend the client connected to')
+            [CompletionResult]::new('-h', '-h', [CompletionResultType]::ParameterName, 'Print help')
+            [CompletionResult]::new('--help', '--help', [CompletionResultType]::ParameterName, 'Print help')
+            break
+        }
+        'tlslb;ech-config' {
+            [CompletionResult]::new('--frontend', '--frontend', [CompletionResultType]::ParameterName, 'Name of the frontend with the ECH keys')
+            [CompletionResult]::new('-h', '-h', [CompletionResultType]::ParameterName, 'Print help')
+            [CompletionResult]::new('--help', '--help', [CompletionResultType]::ParameterName, 'Print help')
+            break
+        }
+        'tlslb;help' {
+            [CompletionResult]::new('route-test', 'route-test', [CompletionResultType]::ParameterValue, 'Show which route a client hello would hit')
+            [CompletionResult]::new('ech-config', 'ech-config', [CompletionResultType]::ParameterValue, 'Print the `ECHConfigList` of a frontend for the `ech` parameter of DNS HTTPS records')
+            [CompletionResult]::new('help', 'help', [CompletionResultType]::ParameterValue, 'Print this message or the help of the given subcommand(s)')
+            break
+        }
+        'tlslb;help;route-test' {
+            break
+        }
+        'tlslb;help;ech-config' {
+            break
+        }
+        'tlslb;help;help' {
             break
         }
     })
@@ -1,3 +1,45 @@
-complete -c tlslb -s c -l config-file -d 'Path to the config file' -r -F
-complete -c tlslb -s h -l help -d 'Print help'
-complete -c tlslb -s V -l version -d 'Print version'
+# Print an optspec for argparse to handle cmd's options that are independent of any subcommand.
+function __fish_tlslb_global_optspecs
+	string join \n c/config-file= h/help V/version
+end
+
+function __fish_tlslb_needs_command
+	# Figure out if the current invocation already has a command.
+	set -l cmd (commandline -opc)
+	set -e cmd[1]
+	argparse -s (__fish_tlslb_global_optspecs) -- $cmd 2>/dev/null
+	or return
+	if set -q argv[1]
+		# Also print the command, so this can be used to figure out what it is.
+		echo $argv[1]
+		return 1
+	end
+	return 0
+end
+
+function __fish_tlslb_using_subcommand
+	set -l cmd (__fish_tlslb_needs_command)
+	test -z "$cmd"
+	and return 1
+	contains -- $cmd[1] $argv
+end
+
+complete -c tlslb -n "__fish_tlslb_needs_command" -s c -l config-file -d 'Path to the config file' -r -F
+complete -c tlslb -n "__fish_tlslb_needs_command" -s h -l help -d 'Print help'
+complete -c tlslb -n "__fish_tlslb_needs_command" -s V -l version -d 'Print version'
+complete -c tlslb -n "__fish_tlslb_needs_command" -f -a "route-test" -d 'Show which route a client hello would hit'
+complete -c tlslb -n "__fish_tlslb_needs_command" -f -a "ech-config" -d 'Print the `ECHConfigList` of a frontend for the `ech` parameter of DNS HTTPS records'
+complete -c tlslb -n "__fish_tlslb_needs_command" -f -a "help" -d 'Print this message or the help of the given subcommand(s)'
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l client-hello -d 'File containing a TLS client hello record, either raw or as hex' -r -F
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l sni -d 'SNI of the client, if no client hello is given' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l alpn -d 'Offered ALPN protocol, can be repeated, if no client hello is given' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l tls-version -d 'Highest TLS version of the client like `1.3`, if no client hello is given' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l ja4 -d 'JA4 fingerprint of the client, if no client hello is given' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l client-ip -d 'Address of the client' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -l frontend -d 'Name of the frontend the client connected to' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand route-test" -s h -l help -d 'Print help'
+complete -c tlslb -n "__fish_tlslb_using_subcommand ech-config" -l frontend -d 'Name of the frontend with the ECH keys' -r
+complete -c tlslb -n "__fish_tlslb_using_subcommand ech-config" -s h -l help -d 'Print help'
+complete -c tlslb -n "__fish_tlslb_using_subcommand help; and not __fish_seen_subcommand_from route-test ech-config help" -f -a "route-test" -d 'Show which route a client hello would hit'
+complete -c tlslb -n "__fish_tlslb_using_subcommand help; and not __fish_seen_subcommand_from route-test ech-config help" -f -a "ech-config" -d 'Print the `ECHConfigList` of a frontend for the `ech` parameter of DNS HTTPS records'
+complete -c tlslb -n "__fish_tlslb_using_subcommand help; and not __fish_seen_subcommand_from route-test ech-config help" -f -a "help" -d 'Print this message or the help of the given subcommand(s)'
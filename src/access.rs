@@ -0,0 +1,280 @@
+use std::{collections::HashSet, fs, net::IpAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use parking_lot::RwLock;
+use prefix_trie::PrefixMap;
+use socket2::SockRef;
+use tls_client_hello_parser::{AlertDescription, AlertLevel, encode_alert};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info};
+
+use crate::{
+    config::{AccessList, DenyAction, TlsAlert, UnknownAsn},
+    reload::{FileWatch, Reload},
+    stream::Stream,
+};
+
+/// Allow and deny lists of a frontend or backend, including the entries of reloadable files
+pub struct AccessControl {
+    config: AccessList,
+    files: FileWatch,
+    rules: RwLock<Arc<AccessRules>>,
+}
+
+#[derive(Default)]
+struct AccessRules {
+    allow: NetworkSet,
+    deny: NetworkSet,
+}
+
+#[derive(Default)]
+struct NetworkSet {
+    ipv4: PrefixMap<Ipv4Net, ()>,
+    ipv6: PrefixMap<Ipv6Net, ()>,
+    asns: HashSet<u32>,
+}
+
+impl NetworkSet {
+    fn insert_net(&mut self, net: IpNet) {
+        match net {
+            IpNet::V4(net) => self.ipv4.insert(net, ()),
+            IpNet::V6(net) => self.ipv6.insert(net, ()),
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty() && self.asns.is_empty()
+    }
+
+    fn contains_ip(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.ipv4.get_lpm(&Ipv4Net::from(ip)).is_some(),
+            IpAddr::V6(ip) => self.ipv6.get_lpm(&Ipv6Net::from(ip)).is_some(),
+        }
+    }
+
+    /// Adds the entries of a file, one network like `192.0.2.0/24` or AS like `AS64496` per line
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path).with_context(|| format!("can't read {path:?}"))?;
+        for line in content.lines() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if let Some(asn) = entry
+                .strip_prefix("AS")
+                .or_else(|| entry.strip_prefix("as"))
+            {
+                self.asns.insert(
+                    asn.parse()
+                        .with_context(|| format!("malformed AS {entry:?} in {path:?}"))?,
+                );
+            } else if let Ok(net) = entry.parse::<IpNet>() {
+                self.insert_net(net);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                self.insert_net(IpNet::from(ip));
+            } else {
+                bail!("malformed entry {entry:?} in {path:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AccessControl {
+    pub fn new(config: AccessList) -> Result<Self> {
+        let files = FileWatch::new(
+            config
+                .allow_files
+                .iter()
+                .chain(&config.deny_files)
+                .cloned()
+                .collect(),
+        );
+        let rules = load_rules(&config)?;
+        Ok(Self {
+            config,
+            files,
+            rules: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    /// Checks if a client is allowed
+    pub fn is_allowed(&self, ip: IpAddr, asn: Option<u32>) -> bool {
+        let rules = Arc::clone(&self.rules.read());
+
+        if rules.deny.contains_ip(ip) || asn.is_some_and(|asn| rules.deny.asns.contains(&asn)) {
+            return false;
+        }
+        // an explicit allow rule wins over the unknown AS policy
+        if rules.allow.contains_ip(ip) || asn.is_some_and(|asn| rules.allow.asns.contains(&asn)) {
+            return true;
+        }
+        if asn.is_none() && self.config.unknown_asn == UnknownAsn::Deny {
+            return false;
+        }
+        rules.allow.is_empty()
+    }
+
+    /// Closes the connection of a denied client as configured
+    pub async fn deny(&self, stream: Stream) -> Result<()> {
+        close_denied(
+            stream,
+            self.config.deny_action,
+            self.config.alert.unwrap_or(TlsAlert::AccessDenied),
+        )
+        .await
+    }
+}
+
+impl Reload for AccessControl {
+    fn reload_if_changed(&self) {
+        if !self.files.changed() {
+            return;
+        }
+        match load_rules(&self.config) {
+            Ok(rules) => {
+                info!(files = ?self.files.paths(), "reloaded access lists");
+                *self.rules.write() = Arc::new(rules);
+            }
+            Err(err) => error!(?err, "failed reloading access lists"),
+        }
+    }
+}
+
+/// Closes the connection of a denied client with the given action
+pub async fn close_denied(mut stream: Stream, action: DenyAction, alert: TlsAlert) -> Result<()> {
+    match action {
+        DenyAction::Close => {}
+        DenyAction::Reset => {
+            // a zero linger timeout makes the kernel send a RST on close
+            if let Stream::Tcp(stream) = &stream {
+                SockRef::from(stream).set_linger(Some(Duration::ZERO))?;
+            }
+        }
+        DenyAction::Alert => {
+            stream
+                .write_all(&encode_alert(
+                    AlertLevel::Fatal,
+                    AlertDescription::from(alert),
+                ))
+                .await
+                .context("failed sending TLS alert")?;
+        }
+    }
+    Ok(())
+}
+
+fn load_rules(config: &AccessList) -> Result<AccessRules> {
+    let mut rules = AccessRules::default();
+    for (set, nets, asns, files) in [
+        (
+            &mut rules.allow,
+            &config.allow_cidrs,
+            &config.allow_asns,
+            &config.allow_files,
+        ),
+        (
+            &mut rules.deny,
+            &config.deny_cidrs,
+            &config.deny_asns,
+            &config.deny_files,
+        ),
+    ] {
+        nets.iter().for_each(|net| set.insert_net(*net));
+        set.asns.extend(asns);
+        for file in files {
+            set.load_file(file)?;
+        }
+    }
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn access_control(config: &str) -> AccessControl {
+        AccessControl::new(toml::from_str(config).unwrap()).unwrap()
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 23));
+
+    #[test]
+    fn test_allow_everything_by_default() {
+        let access = access_control("");
+        assert!(access.is_allowed(CLIENT, None));
+        assert!(access.is_allowed(CLIENT, Some(64496)));
+    }
+
+    #[test]
+    fn test_deny_wins() {
+        let access = access_control(
+            r#"
+            allow-cidrs = ["203.0.113.0/24"]
+            deny-cidrs = ["203.0.113.16/28"]
+            "#,
+        );
+        assert!(!access.is_allowed(CLIENT, None));
+        assert!(access.is_allowed(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), None));
+        assert!(!access.is_allowed(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), None));
+        // IPv4-mapped addresses are treated as IPv4
+        assert!(access.is_allowed(
+            IpAddr::V6(Ipv4Addr::new(203, 0, 113, 1).to_ipv6_mapped()),
+            None
+        ));
+        assert!(!access.is_allowed(IpAddr::V6(Ipv6Addr::LOCALHOST), None));
+    }
+
+    #[test]
+    fn test_asns() {
+        let access = access_control(
+            r#"
+            allow-asns = [64496, 64497]
+            deny-asns = [64497]
+            unknown-asn = "deny"
+            "#,
+        );
+        assert!(access.is_allowed(CLIENT, Some(64496)));
+        assert!(!access.is_allowed(CLIENT, Some(64497)));
+        assert!(!access.is_allowed(CLIENT, Some(64498)));
+        assert!(!access.is_allowed(CLIENT, None));
+    }
+
+    #[test]
+    fn test_allowed_ip_without_asn() {
+        let access = access_control(
+            r#"
+            allow-cidrs = ["203.0.113.0/24"]
+            unknown-asn = "deny"
+            "#,
+        );
+        assert!(access.is_allowed(CLIENT, None));
+        assert!(!access.is_allowed(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), None));
+        assert!(!access.is_allowed(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), Some(64496)));
+    }
+
+    #[test]
+    fn test_reload_file() {
+        let path = std::env::temp_dir().join(format!("tlslb-acl-test-{}", std::process::id()));
+        fs::write(&path, "# scrapers\n203.0.113.0/24\nAS64496\n").unwrap();
+
+        let access = access_control(&format!("deny-files = [{path:?}]"));
+        assert!(!access.is_allowed(CLIENT, None));
+        assert!(!access.is_allowed(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), Some(64496)));
+
+        fs::write(&path, "198.51.100.0/24\n").unwrap();
+        // make sure the modification time differs on file systems with coarse timestamps
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        access.reload_if_changed();
+        assert!(access.is_allowed(CLIENT, None));
+        assert!(!access.is_allowed(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), None));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Sleep, sleep},
+};
+
+use crate::config::Bandwidth;
+
+/// Shortest time a throttled stream sleeps, to avoid waking up for single bytes
+const MIN_DELAY: Duration = Duration::from_millis(1);
+
+/// Token bucket of bytes, shared by all streams it applies to
+///
+/// Streams may take more bytes than available, the debt delays the next transfer.
+pub struct ByteBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl ByteBucket {
+    /// Creates a full bucket, the burst size is one second of the rate
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second as f64;
+        Self {
+            rate,
+            burst: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Time until bytes can be transferred again
+    fn delay(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock();
+        let (tokens, updated) = &mut *state;
+        let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.burst);
+        *updated = now;
+        (*tokens <= 0.0).then(|| Duration::from_secs_f64(-*tokens / self.rate).max(MIN_DELAY))
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn consume(&self, bytes: usize) {
+        self.state.lock().0 -= bytes as f64;
+    }
+}
+
+/// The buckets that apply to a single connection
+#[derive(Clone, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<ByteBucket>>,
+    /// Time streams spent waiting for the buckets
+    throttled_nanos: Arc<AtomicU64>,
+}
+
+impl Throttle {
+    fn delay(&self, now: Instant) -> Option<Duration> {
+        self.buckets
+            .iter()
+            .filter_map(|bucket| bucket.delay(now))
+            .max()
+    }
+
+    fn consume(&self, bytes: usize) {
+        for bucket in &self.buckets {
+            bucket.consume(bytes);
+        }
+    }
+}
+
+/// Bandwidth limits of a pool, shared by all its connections
+pub struct Shaper {
+    config: Bandwidth,
+    pool_bucket: Option<Arc<ByteBucket>>,
+    asn_buckets: Mutex<HashMap<u32, Weak<ByteBucket>>>,
+    throttled_nanos: Arc<AtomicU64>,
+}
+
+impl Shaper {
+    pub fn new(config: Bandwidth) -> Result<Self> {
+        if [config.per_connection, config.per_pool, config.per_asn].contains(&Some(0)) {
+            bail!("bandwidth limit must be positive");
+        }
+        Ok(Self {
+            pool_bucket: config.per_pool.map(|rate| Arc::new(ByteBucket::new(rate))),
+            config,
+            asn_buckets: Mutex::default(),
+            throttled_nanos: Arc::default(),
+        })
+    }
+
+    /// Collects the buckets for a new connection of a client
+    pub fn throttle(&self, client_asn: Option<u32>) -> Throttle {
+        let mut buckets = Vec::with_capacity(3);
+        if let Some(rate) = self.config.per_connection {
+            buckets.push(Arc::new(ByteBucket::new(rate)));
+        }
+        buckets.extend(self.pool_bucket.clone());
+        if let (Some(rate), Some(asn)) = (self.config.per_asn, client_asn) {
+            let mut asn_buckets = self.asn_buckets.lock();
+            let bucket = match asn_buckets.get(&asn).and_then(Weak::upgrade) {
+                Some(bucket) => bucket,
+                None => {
+                    // forget the buckets of autonomous systems without connections
+                    asn_buckets.retain(|_asn, bucket| bucket.strong_count() > 0);
+                    let bucket = Arc::new(ByteBucket::new(rate));
+                    asn_buckets.insert(asn, Arc::downgrade(&bucket));
+                    bucket
+                }
+            };
+            buckets.push(bucket);
+        }
+        Throttle {
+            buckets,
+            throttled_nanos: Arc::clone(&self.throttled_nanos),
+        }
+    }
+
+    /// Total time connections of this pool were throttled
+    pub fn throttled(&self) -> Duration {
+        Duration::from_nanos(self.throttled_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// A stream whose reads and writes are limited by a [`Throttle`]
+pub struct ThrottledStream<S> {
+    inner: S,
+    throttle: Throttle,
+    sleep: Option<(Pin<Box<Sleep>>, Instant)>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub const fn new(inner: S, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            sleep: None,
+        }
+    }
+
+    /// Waits until all buckets have bytes available
+    fn poll_throttle(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some((sleep, start)) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                #[allow(clippy::cast_possible_truncation)]
+                let throttled = start.elapsed().as_nanos() as u64;
+                self.throttle
+                    .throttled_nanos
+                    .fetch_add(throttled, Ordering::Relaxed);
+                self.sleep = None;
+            }
+            let now = Instant::now();
+            match self.throttle.delay(now) {
+                None => return Poll::Ready(()),
+                Some(delay) => self.sleep = Some((Box::pin(sleep(delay)), now)),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_throttle(cx));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.throttle.consume(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_throttle(cx));
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.throttle.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn test_bucket_debt() {
+        let bucket = ByteBucket::new(1000);
+        let start = Instant::now();
+        assert_eq!(bucket.delay(start), None);
+
+        bucket.consume(1500);
+        let delay = bucket.delay(start).unwrap();
+        assert!(delay >= Duration::from_millis(499) && delay <= Duration::from_millis(501));
+        assert_eq!(bucket.delay(start + Duration::from_millis(600)), None);
+    }
+
+    #[test]
+    fn test_zero_bandwidth() {
+        for bandwidth in [
+            Bandwidth {
+                per_connection: Some(0),
+                per_pool: None,
+                per_asn: None,
+            },
+            Bandwidth {
+                per_connection: None,
+                per_pool: Some(0),
+                per_asn: None,
+            },
+            Bandwidth {
+                per_connection: Some(1000),
+                per_pool: None,
+                per_asn: Some(0),
+            },
+        ] {
+            assert!(Shaper::new(bandwidth).is_err());
+        }
+    }
+
+    #[test]
+    fn test_asn_buckets_are_shared() {
+        let shaper = Shaper::new(Bandwidth {
+            per_connection: None,
+            per_pool: None,
+            per_asn: Some(1000),
+        })
+        .unwrap();
+        let first = shaper.throttle(Some(64496));
+        let second = shaper.throttle(Some(64496));
+        assert!(Arc::ptr_eq(&first.buckets[0], &second.buckets[0]));
+        assert!(shaper.throttle(None).buckets.is_empty());
+
+        drop((first, second));
+        let third = shaper.throttle(Some(64497));
+        assert_eq!(third.buckets.len(), 1);
+        assert_eq!(shaper.asn_buckets.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_read() {
+        let shaper = Shaper::new(Bandwidth {
+            per_connection: Some(10_000),
+            per_pool: None,
+            per_asn: None,
+        })
+        .unwrap();
+        let data = vec![0u8; 15_000];
+        let mut stream = ThrottledStream::new(&data[..], shaper.throttle(None));
+
+        let start = Instant::now();
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out.len(), data.len());
+        // the first 10000 bytes are the burst, the rest takes half a second
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert!(shaper.throttled() >= Duration::from_millis(400));
+    }
+}
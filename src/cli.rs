@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser, Debug, PartialEq, Eq)]
 #[command(author, version, about, long_about = None)]
@@ -9,4 +9,46 @@ pub struct Cli {
     /// Path to the config file
     #[arg(short, long, value_name = "CONFIG_FILE")]
     pub config_file: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Show which route a client hello would hit
+    RouteTest(RouteTestArgs),
+    /// Print the `ECHConfigList` of a frontend for the `ech` parameter of DNS HTTPS records
+    EchConfig(EchConfigArgs),
+}
+
+#[derive(Args, Debug, PartialEq, Eq)]
+pub struct EchConfigArgs {
+    /// Name of the frontend with the ECH keys
+    #[arg(long, default_value = "https")]
+    pub frontend: String,
+}
+
+#[derive(Args, Debug, PartialEq, Eq)]
+pub struct RouteTestArgs {
+    /// File containing a TLS client hello record, either raw or as hex
+    #[arg(long, value_name = "FILE")]
+    pub client_hello: Option<PathBuf>,
+    /// SNI of the client, if no client hello is given
+    #[arg(long, conflicts_with = "client_hello")]
+    pub sni: Option<String>,
+    /// Offered ALPN protocol, can be repeated, if no client hello is given
+    #[arg(long, conflicts_with = "client_hello")]
+    pub alpn: Vec<String>,
+    /// Highest TLS version of the client like `1.3`, if no client hello is given
+    #[arg(long, default_value = "1.3", conflicts_with = "client_hello")]
+    pub tls_version: String,
+    /// JA4 fingerprint of the client, if no client hello is given
+    #[arg(long, default_value = "", conflicts_with = "client_hello")]
+    pub ja4: String,
+    /// Address of the client
+    #[arg(long, default_value = "192.0.2.1")]
+    pub client_ip: IpAddr,
+    /// Name of the frontend the client connected to
+    #[arg(long, default_value = "https")]
+    pub frontend: String,
 }
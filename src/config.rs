@@ -1,37 +1,645 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use ipnet::IpNet;
 use serde::Deserialize;
+use tls_client_hello_parser::AlertDescription;
 
 #[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
-    pub frontends: Frontends,
+    /// Address of the HTTP listener serving metrics in the Prometheus text format
+    #[serde(default)]
+    pub metrics_address: Option<SocketAddr>,
+    pub frontends: HashMap<String, Frontend>,
     pub backends: HashMap<String, Arc<Backend>>,
+    /// Interval in seconds in which files referenced by the configuration are checked for changes
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    /// Ordered routing rules, the first matching rule wins
+    ///
+    /// If no rule matches, the backend named like the SNI is used.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Resolver used for backend addresses and dynamic backends
+    #[serde(default)]
+    pub dns: DnsConfig,
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-pub struct Frontends {
-    pub https: Frontend,
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct DnsConfig {
+    /// Nameservers like `[2001:db8::53]:53`, the system configuration is used if empty
+    pub nameservers: Vec<SocketAddr>,
+    /// Count of records held in the cache
+    pub cache_size: usize,
+    /// Timeout of a single query in milliseconds
+    pub timeout_ms: u64,
+    /// Time in seconds names that do not exist are cached
+    pub negative_ttl_secs: u64,
+    /// Upper bound of the time in seconds records are cached, the TTL of the record if not set
+    pub max_ttl_secs: Option<u64>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            cache_size: 1024,
+            timeout_ms: 2000,
+            negative_ttl_secs: 30,
+            max_ttl_secs: None,
+        }
+    }
+}
+
+const fn default_reload_interval_secs() -> u64 {
+    10
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Frontend {
-    pub listen_address: SocketAddr,
+    /// Protocol spoken by the clients
+    #[serde(default, rename = "type")]
+    pub kind: FrontendKind,
+    pub listen_address: ListenAddress,
+    /// Permissions and ownership of the socket file of Unix socket frontends
+    #[serde(default)]
+    pub unix_socket: UnixSocket,
+    /// Backend all connections are forwarded to, for frontends without routing
+    ///
+    /// HTTP frontends use it for hosts without a backend.
+    #[serde(default)]
+    pub backend: Option<String>,
+    /// Clients allowed to connect, checked before anything is read
+    #[serde(default)]
+    pub access: AccessList,
+    /// Rules for JA4 fingerprints, checked before routing
+    #[serde(default)]
+    pub ja4_policy: Option<Ja4PolicyConfig>,
+    /// Limits of new connections and concurrent connections per client
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimit>,
+    /// Client connections handled at once, new connections are not accepted above
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Accept connections redirected by TPROXY to addresses that are not local (`IP_TRANSPARENT`)
+    #[serde(default)]
+    pub transparent: bool,
+    /// Options of the client connections, set on the listen socket and inherited
+    #[serde(default)]
+    pub socket_options: SocketOptions,
+    /// Length of the queue of connections not accepted yet
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+    /// Seconds the kernel waits for data before a connection is accepted (`TCP_DEFER_ACCEPT`)
+    #[serde(default)]
+    pub defer_accept_secs: Option<u32>,
+    /// Length of the queue of pending TCP Fast Open requests, enables TFO if set
+    #[serde(default)]
+    pub fastopen_queue: Option<u32>,
+    /// Count of listen sockets with `SO_REUSEPORT`, each with its own accept loop
+    #[serde(default)]
+    pub reuse_port: Option<usize>,
+    /// Settings of HTTP frontends
+    #[serde(default)]
+    pub http: HttpFrontend,
+    /// Settings of QUIC frontends
+    #[serde(default)]
+    pub quic: QuicFrontend,
+    /// Settings of SMTP, IMAP, POP3 and XMPP frontends
+    #[serde(default)]
+    pub starttls: StartTlsFrontend,
+    /// Settings of HTTP `CONNECT` and SOCKS5 frontends
+    #[serde(default)]
+    pub tunnel: TunnelFrontend,
+    /// Alerts sent to TLS clients that can not be routed
+    #[serde(default)]
+    pub alerts: Alerts,
+    /// Backend of TLS clients without SNI, like clients connecting to an IP address
+    #[serde(default)]
+    pub no_sni: NoSniPolicy,
+    /// Keys to decrypt the inner client hello of Encrypted Client Hello
+    #[serde(default)]
+    pub ech: Vec<EchKey>,
+}
+
+/// HPKE key of Encrypted Client Hello, tlslb is the client-facing server in split mode
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct EchKey {
+    /// Identifier of the key in the client hello
+    pub config_id: u8,
+    /// SNI of the outer client hello, a backend needs a certificate for it
+    pub public_name: String,
+    /// PEM file with the X25519 private key in PKCS #8
+    pub private_key: PathBuf,
+    /// Include the key in the published ECHConfigList, old keys can be kept for decryption
+    #[serde(default = "default_ech_publish")]
+    pub publish: bool,
+}
+
+const fn default_ech_publish() -> bool {
+    true
+}
+
+/// How TLS clients without SNI are routed if no route matches
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(
+    tag = "action",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub enum NoSniPolicy {
+    /// Send the alert `alerts.missing-sni` and close the connection
+    #[default]
+    Reject,
+    /// Forward the connection to a backend
+    Forward { backend: String },
+    /// Select the backend by the local address the client connected to
+    ByDestination {
+        backends: HashMap<IpAddr, String>,
+        /// Backend for other addresses and Unix sockets, they are rejected if not set
+        #[serde(default)]
+        backend: Option<String>,
+    },
+}
+
+impl NoSniPolicy {
+    /// Backend of a client that connected to `local_ip`, `None` if the client is rejected
+    ///
+    /// Clients of Unix sockets have no `local_ip` and get the default backend.
+    pub fn backend(&self, local_ip: Option<IpAddr>) -> Option<&str> {
+        match self {
+            Self::Reject => None,
+            Self::Forward { backend } => Some(backend),
+            Self::ByDestination { backends, backend } => local_ip
+                .and_then(|local_ip| backends.get(&local_ip.to_canonical()))
+                .or(backend.as_ref())
+                .map(String::as_str),
+        }
+    }
+
+    /// All backends the policy can select
+    pub fn backends(&self) -> Vec<&String> {
+        match self {
+            Self::Reject => Vec::new(),
+            Self::Forward { backend } => vec![backend],
+            Self::ByDestination { backends, backend } => backends.values().chain(backend).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Alerts {
+    /// No route and no backend matches the SNI
+    pub unknown_domain: TlsAlert,
+    /// The client hello has no SNI, no route matches and `no-sni` rejects the client
+    pub missing_sni: TlsAlert,
+    /// SSL 2.0 and SSL 3.0 clients
+    pub unsupported_version: TlsAlert,
+    /// The client did not send a valid client hello
+    pub malformed_client_hello: TlsAlert,
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            unknown_domain: TlsAlert::UnrecognizedName,
+            missing_sni: TlsAlert::UnrecognizedName,
+            unsupported_version: TlsAlert::ProtocolVersion,
+            malformed_client_hello: TlsAlert::DecodeError,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TunnelFrontend {
+    /// Ports clients may connect to
+    #[serde(default = "default_tunnel_allowed_ports")]
+    pub allowed_ports: Vec<u16>,
+    /// Require the SNI of the client hello in the tunnel to be the requested host
+    #[serde(default)]
+    pub check_sni: bool,
+}
+
+impl Default for TunnelFrontend {
+    fn default() -> Self {
+        Self {
+            allowed_ports: default_tunnel_allowed_ports(),
+            check_sni: false,
+        }
+    }
+}
+
+fn default_tunnel_allowed_ports() -> Vec<u16> {
+    vec![443]
+}
+
+/// Prefix of addresses of Unix sockets
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Path of a Unix socket address like `unix:/run/backend.sock`
+pub fn unix_path(address: &str) -> Option<&Path> {
+    address
+        .strip_prefix(UNIX_PREFIX)
+        .filter(|path| !path.is_empty())
+        .map(Path::new)
+}
+
+/// Address a frontend listens on, an IP address with port or `unix:` and the path of a Unix socket
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(try_from = "String")]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    pub const fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(addr) => Some(*addr),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        if address.starts_with(UNIX_PREFIX) {
+            return unix_path(&address)
+                .map(|path| Self::Unix(path.to_path_buf()))
+                .ok_or_else(|| format!("{address:?} has no path"));
+        }
+        address
+            .parse()
+            .map(Self::Tcp)
+            .map_err(|err| format!("invalid listen address {address:?}: {err}"))
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UnixSocket {
+    /// Permissions of the socket file like `0o660`, as created by the umask if not set
+    pub mode: Option<u32>,
+    /// User name or ID owning the socket file
+    pub owner: Option<String>,
+    /// Group name or ID of the socket file
+    pub group: Option<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct StartTlsFrontend {
+    /// Name of the server in the greeting
+    #[serde(default = "default_starttls_hostname")]
+    pub hostname: String,
+    /// Seconds the client and the backend have until TLS is started
+    #[serde(default = "default_starttls_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for StartTlsFrontend {
+    fn default() -> Self {
+        Self {
+            hostname: default_starttls_hostname(),
+            timeout_secs: default_starttls_timeout_secs(),
+        }
+    }
+}
+
+fn default_starttls_hostname() -> String {
+    "localhost".to_string()
+}
+
+const fn default_starttls_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct QuicFrontend {
+    /// Seconds without datagrams in either direction after which a flow is forgotten
+    #[serde(default = "default_quic_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for QuicFrontend {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: default_quic_idle_timeout_secs(),
+        }
+    }
+}
+
+const fn default_quic_idle_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct HttpFrontend {
+    /// Largest request head that is read to find the `Host` header
+    #[serde(default = "default_max_head_size")]
+    pub max_head_size: usize,
+    /// Answer all requests with a redirect to HTTPS instead of forwarding them
+    #[serde(default)]
+    pub redirect_to_https: Option<RedirectStatus>,
+    /// Backend answering ACME HTTP-01 challenges, also if requests are redirected
+    #[serde(default)]
+    pub acme_backend: Option<String>,
+}
+
+impl Default for HttpFrontend {
+    fn default() -> Self {
+        Self {
+            max_head_size: default_max_head_size(),
+            redirect_to_https: None,
+            acme_backend: None,
+        }
+    }
+}
+
+const fn default_max_head_size() -> usize {
+    16384
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(try_from = "u16")]
+pub enum RedirectStatus {
+    /// 301
+    MovedPermanently,
+    /// 308, keeps the method and body
+    PermanentRedirect,
+}
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(status: u16) -> Result<Self, Self::Error> {
+        match status {
+            301 => Ok(Self::MovedPermanently),
+            308 => Ok(Self::PermanentRedirect),
+            _ => Err(format!("redirect status must be 301 or 308, not {status}")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrontendKind {
+    /// Route by the TLS client hello
+    #[default]
+    Tls,
+    /// Forward plain TCP to a fixed backend without looking at the data
+    Tcp,
+    /// Route HTTP/1.x requests by the `Host` header
+    Http,
+    /// Route QUIC connections on UDP by the client hello in their Initial packets
+    Quic,
+    /// Answer SMTP until `STARTTLS`, then route by the TLS client hello
+    Smtp,
+    /// Answer IMAP until `STARTTLS`, then route by the TLS client hello
+    Imap,
+    /// Answer POP3 until `STLS`, then route by the TLS client hello
+    Pop3,
+    /// Answer XMPP until `<starttls/>`, then route by the TLS client hello
+    Xmpp,
+    /// Answer the `SSLRequest` of PostgreSQL, then route by the TLS client hello
+    Postgres,
+    /// Explicit proxy for HTTP `CONNECT` requests, routing by the requested host
+    Connect,
+    /// Explicit SOCKS5 proxy, routing by the requested domain name
+    Socks5,
+}
+
+const fn default_backlog() -> u32 {
+    1024
+}
+
+/// TCP options of the connections of a frontend or backend
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SocketOptions {
+    /// Idle seconds before keepalive probes are sent, enables keepalive
+    #[serde(default)]
+    pub keepalive_time_secs: Option<u64>,
+    /// Seconds between keepalive probes
+    #[serde(default)]
+    pub keepalive_interval_secs: Option<u64>,
+    /// Unanswered keepalive probes before the connection is dropped
+    #[serde(default)]
+    pub keepalive_count: Option<u32>,
+    /// Milliseconds sent data may stay unacknowledged before the connection is dropped
+    #[serde(default)]
+    pub user_timeout_ms: Option<u64>,
+    /// Unsent bytes above which the socket is not writable (`TCP_NOTSENT_LOWAT`)
+    #[serde(default)]
+    pub notsent_lowat: Option<u32>,
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+    /// Congestion control algorithm like `bbr` or `cubic`
+    #[serde(default)]
+    pub congestion_control: Option<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessList {
+    /// If any allow list is set, only clients on one of them are allowed
+    #[serde(default)]
+    pub allow_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub allow_asns: Vec<u32>,
+    /// Files with one network like `192.0.2.0/24` or AS like `AS64496` per line
+    ///
+    /// The files are reloaded when they change.
+    #[serde(default)]
+    pub allow_files: Vec<PathBuf>,
+    /// Clients on a deny list are denied, even if they are on an allow list
+    #[serde(default)]
+    pub deny_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub deny_asns: Vec<u32>,
+    #[serde(default)]
+    pub deny_files: Vec<PathBuf>,
+    /// Handling of clients whose address is not announced by any AS
+    #[serde(default)]
+    pub unknown_asn: UnknownAsn,
+    /// How the connection of a denied client is closed
+    #[serde(default)]
+    pub deny_action: DenyAction,
+    /// Alert sent with the `alert` deny action, `access-denied` if not set
+    #[serde(default)]
+    pub alert: Option<TlsAlert>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Ja4PolicyConfig {
+    /// Rules like `deny t13d1516h2_8daaf6152771_*`, applied in order before the rules of the files
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// Files with one rule per line, reloaded when they change
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    /// Backend for clients matching a `suspicious` rule
+    #[serde(default)]
+    pub suspicious_backend: Option<String>,
+    /// How the connection of a denied client is closed
+    #[serde(default)]
+    pub deny_action: DenyAction,
+    /// Alert sent with the `alert` deny action, `handshake-failure` if not set
+    #[serde(default)]
+    pub alert: Option<TlsAlert>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimit {
+    /// What identifies a client
+    pub key: RateLimitKey,
+    /// New connections per second
+    #[serde(default)]
+    pub rate: Option<f64>,
+    /// New connections allowed at once, defaults to `rate`
+    #[serde(default)]
+    pub burst: Option<f64>,
+    /// Concurrent connections
+    #[serde(default)]
+    pub max_concurrent: Option<u32>,
+    /// Prefix length of IPv4 clients for the `prefix` key
+    #[serde(default = "default_ipv4_prefix_len")]
+    pub ipv4_prefix_len: u8,
+    /// Prefix length of IPv6 clients for the `prefix` key
+    #[serde(default = "default_ipv6_prefix_len")]
+    pub ipv6_prefix_len: u8,
+    /// How the connection of a limited client is closed
+    #[serde(default)]
+    pub deny_action: DenyAction,
+    /// Alert sent with the `alert` deny action, `handshake-failure` if not set
+    #[serde(default)]
+    pub alert: Option<TlsAlert>,
+}
+
+const fn default_ipv4_prefix_len() -> u8 {
+    24
+}
+
+const fn default_ipv6_prefix_len() -> u8 {
+    48
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKey {
+    Ip,
+    /// The network of the client, `/24` for IPv4 and `/48` for IPv6 by default
+    Prefix,
+    /// The autonomous system announcing the client address
+    ///
+    /// Clients without a known AS are not limited.
+    Asn,
+    /// The JA4 fingerprint, only known after the client hello was read
+    Ja4,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownAsn {
+    /// Only the allow and deny lists of networks apply
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DenyAction {
+    /// Close the connection
+    #[default]
+    Close,
+    /// Abort the connection with a TCP RST
+    Reset,
+    /// Send a TLS alert, then close the connection
+    Alert,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Backend {
+    /// How the backend addresses are found
+    #[serde(default, rename = "type")]
+    pub kind: BackendKind,
     /// List of addresses to connect to
     ///
-    /// Either a socket address like `192.0.2.0:8443` or `[2001:db8::1]:8443`
-    /// or an address like `backend.tld:8443` that will result in a DNS lookup
+    /// Either a socket address like `192.0.2.0:8443` or `[2001:db8::1]:8443`,
+    /// an address like `backend.tld:8443` that will result in a DNS lookup,
+    /// an SRV record like `_https._tcp.service.internal`
+    /// or the path of a Unix socket like `unix:/run/nginx.sock`
     ///
     /// The set of all addresses/DNS responses will be used.
     /// If one address appears multiple times during the lookup, it will only be used once.
+    #[serde(default)]
     pub addresses: Vec<String>,
+    /// Domain patterns a dynamic backend may connect to, like `*.example.com`
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Address family used to connect to the backend
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    /// Time in milliseconds after which the next address of a host is tried in parallel
+    #[serde(default = "default_happy_eyeballs_delay_ms")]
+    pub happy_eyeballs_delay_ms: u64,
+    /// Port a dynamic backend connects to, the port of the frontend if not set
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Allow a dynamic backend to connect to private, loopback and other non-public addresses
+    #[serde(default)]
+    pub allow_private_addresses: bool,
+    /// Local addresses connections to the backend originate from, at most one per address family
+    #[serde(default)]
+    pub source_address: Vec<IpAddr>,
+    /// Network interface or VRF connections to the backend are bound to (`SO_BINDTODEVICE`)
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// Firewall mark of connections to the backend (`SO_MARK`)
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+    /// First and last local port used for connections to the backend
+    #[serde(default)]
+    pub source_port_range: Option<[u16; 2]>,
+    /// Connect from the address of the client (`IP_TRANSPARENT`), for backends without PROXY protocol
+    #[serde(default)]
+    pub transparent: bool,
+    /// Options of the connections to the backend, keepalive is sent after 30 seconds by default
+    #[serde(default)]
+    pub socket_options: SocketOptions,
+    /// Send the client hello with the SYN if the backend supports TCP Fast Open
+    #[serde(default)]
+    pub fastopen: bool,
     /// Open the TLS connection itself in case of an error even if SNI routing is used
     /// Overwrites the setting from the frontend
     #[serde(default)]
@@ -44,4 +652,286 @@ pub struct Backend {
     /// connections on the frontend, which can result in faster round trip times.
     #[serde(default)]
     pub preconnect_count: Option<usize>,
+    /// Size the idle pool from the recent connection arrival rate instead of a fixed count
+    ///
+    /// Overwrites `preconnect-count` if set.
+    #[serde(default)]
+    pub adaptive_preconnect: Option<AdaptivePreconnect>,
+    /// Forward connections to another backend depending on the ALPN protocols offered by the client
+    ///
+    /// The first matching entry wins. If none matches, this backend is used.
+    #[serde(default)]
+    pub alpn: Vec<AlpnRoute>,
+    /// Clients allowed to use this backend
+    #[serde(default)]
+    pub access: AccessList,
+    /// Rules for JA4 fingerprints, checked after routing
+    #[serde(default)]
+    pub ja4_policy: Option<Ja4PolicyConfig>,
+    /// Limits of new connections and concurrent connections per client
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimit>,
+    /// Client connections forwarded at once, further clients wait in a queue
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Clients that can wait for a connection slot, further clients are rejected
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+    /// Time in milliseconds a client waits for a connection slot before it is rejected
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    /// Limits of the bytes transferred in both directions
+    #[serde(default)]
+    pub bandwidth: Option<Bandwidth>,
+    /// Send a PROXY protocol header with the client address before the client hello
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Bandwidth {
+    /// Bytes per second of every single connection
+    #[serde(default)]
+    pub per_connection: Option<u64>,
+    /// Bytes per second of all connections of the backend together
+    #[serde(default)]
+    pub per_pool: Option<u64>,
+    /// Bytes per second of all connections of clients from the same AS together
+    #[serde(default)]
+    pub per_asn: Option<u64>,
+}
+
+const fn default_max_queued() -> usize {
+    100
+}
+
+const fn default_queue_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyProtocolVersion {
+    /// Binary header, which can carry the SNI and JA4 tags as TLVs
+    V2,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Connect to the configured `addresses`
+    #[default]
+    Static,
+    /// Connect to the addresses the SNI of the client resolves to
+    Dynamic,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressFamily {
+    /// Use both families, IPv6 first
+    #[default]
+    PreferIpv6,
+    /// Use both families, IPv4 first
+    PreferIpv4,
+    Ipv6Only,
+    Ipv4Only,
+}
+
+impl AddressFamily {
+    pub fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            Self::PreferIpv6 | Self::PreferIpv4 => true,
+            Self::Ipv6Only => ip.to_canonical().is_ipv6(),
+            Self::Ipv4Only => ip.to_canonical().is_ipv4(),
+        }
+    }
+
+    pub const fn prefers_ipv6(self) -> bool {
+        !matches!(self, Self::PreferIpv4 | Self::Ipv4Only)
+    }
+}
+
+/// Connection attempt delay recommended by RFC 8305
+const fn default_happy_eyeballs_delay_ms() -> u64 {
+    250
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AlpnRoute {
+    /// ALPN protocol IDs like `h2`, `http/1.1` or `acme-tls/1`
+    pub protocols: Vec<String>,
+    /// Which of the offered protocols are compared
+    #[serde(default, rename = "match")]
+    pub match_on: AlpnMatch,
+    /// Name of the backend the connection is forwarded to
+    pub backend: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlpnMatch {
+    /// Only the protocol the client prefers
+    #[default]
+    First,
+    /// Any protocol offered by the client
+    Any,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdaptivePreconnect {
+    /// Lower bound of idle connections held in the pool
+    #[serde(default)]
+    pub min: usize,
+    /// Upper bound of idle connections held in the pool
+    pub max: usize,
+    /// Factor applied to the Little's law estimate to absorb bursts
+    #[serde(default = "default_headroom")]
+    pub headroom: f64,
+    /// Weight of the newest sample in the moving averages, between 0 and 1
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    /// Interval in milliseconds in which the target size is recalculated
+    #[serde(default = "default_adjust_interval_ms")]
+    pub adjust_interval_ms: u64,
+}
+
+const fn default_headroom() -> f64 {
+    2.0
+}
+
+const fn default_smoothing() -> f64 {
+    0.3
+}
+
+const fn default_adjust_interval_ms() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Route {
+    /// Name shown in logs and by `tlslb route-test`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// SNI patterns like `example.com`, `*.example.com` or `*`
+    #[serde(default)]
+    pub sni: Vec<String>,
+    /// ALPN protocol IDs like `h2` or `acme-tls/1`
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// Which of the offered ALPN protocols are compared
+    #[serde(default)]
+    pub alpn_match: AlpnMatch,
+    /// Highest TLS versions supported by the client
+    #[serde(default)]
+    pub tls_versions: Vec<TlsVersion>,
+    /// Complete JA4 fingerprints
+    #[serde(default)]
+    pub ja4: Vec<String>,
+    /// Prefixes of JA4 fingerprints like `t13d`
+    #[serde(default)]
+    pub ja4_prefix: Vec<String>,
+    /// Networks the client address has to be part of
+    #[serde(default)]
+    pub client_cidrs: Vec<IpNet>,
+    /// Autonomous systems the client address has to be announced by
+    #[serde(default)]
+    pub client_asns: Vec<u32>,
+    /// Names of the frontends the client connected to
+    #[serde(default)]
+    pub frontends: Vec<String>,
+    #[serde(flatten)]
+    pub action: RouteAction,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(
+    tag = "action",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub enum RouteAction {
+    /// Forward the connection to a backend
+    Forward { backend: String },
+    /// Answer with a TLS alert and close the connection
+    Reject {
+        #[serde(default = "default_reject_alert")]
+        alert: TlsAlert,
+    },
+    /// Terminate TLS with a local certificate and forward the plaintext to a backend
+    Terminate {
+        /// PEM file containing the certificate chain
+        certificate: PathBuf,
+        /// PEM file containing the private key
+        private_key: PathBuf,
+        backend: String,
+    },
+    /// Hold the connection open without answering, then close it
+    Tarpit {
+        #[serde(default = "default_tarpit_secs")]
+        duration_secs: u64,
+    },
+}
+
+const fn default_reject_alert() -> TlsAlert {
+    TlsAlert::HandshakeFailure
+}
+
+const fn default_tarpit_secs() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// Version number as it appears on the wire
+    pub const fn wire_version(self) -> u16 {
+        match self {
+            Self::Tls10 => 0x0301,
+            Self::Tls11 => 0x0302,
+            Self::Tls12 => 0x0303,
+            Self::Tls13 => 0x0304,
+        }
+    }
+}
+
+/// TLS alerts that can be sent to a client
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsAlert {
+    HandshakeFailure,
+    AccessDenied,
+    DecodeError,
+    ProtocolVersion,
+    InternalError,
+    UnrecognizedName,
+    NoApplicationProtocol,
+}
+
+impl From<TlsAlert> for AlertDescription {
+    fn from(alert: TlsAlert) -> Self {
+        match alert {
+            TlsAlert::HandshakeFailure => Self::HandshakeFailure,
+            TlsAlert::AccessDenied => Self::AccessDenied,
+            TlsAlert::DecodeError => Self::DecodeError,
+            TlsAlert::ProtocolVersion => Self::ProtocolVersion,
+            TlsAlert::InternalError => Self::InternalError,
+            TlsAlert::UnrecognizedName => Self::UnrecognizedName,
+            TlsAlert::NoApplicationProtocol => Self::NoApplicationProtocol,
+        }
+    }
 }
@@ -0,0 +1,340 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use hickory_resolver::{
+    ResolveError, TokioResolver,
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    name_server::TokioConnectionProvider,
+};
+use tracing::debug;
+
+use crate::config::DnsConfig;
+
+/// Asynchronous resolver with a cache respecting the TTLs of the records
+pub struct DnsResolver {
+    resolver: TokioResolver,
+}
+
+/// A backend address found by a lookup
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ResolvedAddress {
+    /// The configured address or the target of the SRV record, shared by all its addresses
+    pub host: String,
+    pub addr: SocketAddr,
+    /// SRV priority, lower is preferred, 0 for other lookups
+    pub priority: u16,
+    /// SRV weight, relative to the other addresses of the same priority, 1 for other lookups
+    pub weight: u16,
+}
+
+impl DnsResolver {
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        let mut builder = if config.nameservers.is_empty() {
+            TokioResolver::builder_tokio().context("failed reading the system DNS configuration")?
+        } else {
+            let mut nameservers = NameServerConfigGroup::new();
+            for nameserver in &config.nameservers {
+                nameservers.merge(NameServerConfigGroup::from_ips_clear(
+                    &[nameserver.ip()],
+                    nameserver.port(),
+                    true,
+                ));
+            }
+            TokioResolver::builder_with_config(
+                ResolverConfig::from_parts(None, Vec::new(), nameservers),
+                TokioConnectionProvider::default(),
+            )
+        };
+
+        let options: &mut ResolverOpts = builder.options_mut();
+        options.cache_size = config.cache_size;
+        options.timeout = Duration::from_millis(config.timeout_ms);
+        options.negative_min_ttl = Some(Duration::from_secs(config.negative_ttl_secs));
+        options.negative_max_ttl = Some(Duration::from_secs(config.negative_ttl_secs));
+        options.positive_max_ttl = config.max_ttl_secs.map(Duration::from_secs);
+
+        Ok(Self {
+            resolver: builder.build(),
+        })
+    }
+
+    /// Resolves a host name to all its IPv6 and IPv4 addresses
+    pub async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+        Ok(self.resolver.lookup_ip(host).await?.iter().collect())
+    }
+
+    /// Resolves a backend address
+    ///
+    /// Either a socket address, a host name with port like `backend.tld:8443`
+    /// or an SRV record name without port like `_https._tcp.service.internal`.
+    pub async fn resolve_backend(&self, address: &str) -> Result<Vec<ResolvedAddress>> {
+        if let Ok(addr) = address.parse() {
+            return Ok(vec![ResolvedAddress {
+                host: address.to_string(),
+                addr,
+                priority: 0,
+                weight: 1,
+            }]);
+        }
+        if address.starts_with('_') && !address.contains(':') {
+            return self.resolve_srv(address).await;
+        }
+
+        let (host, port) = address
+            .rsplit_once(':')
+            .with_context(|| format!("backend address {address:?} has no port"))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in backend address {address:?}"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok(self
+            .lookup_ip(host)
+            .await
+            .with_context(|| format!("failed resolving {host:?}"))?
+            .into_iter()
+            .map(|ip| ResolvedAddress {
+                host: address.to_string(),
+                addr: SocketAddr::new(ip, port),
+                priority: 0,
+                weight: 1,
+            })
+            .collect())
+    }
+
+    async fn resolve_srv(&self, name: &str) -> Result<Vec<ResolvedAddress>> {
+        let records = self
+            .resolver
+            .srv_lookup(name)
+            .await
+            .with_context(|| format!("failed resolving SRV record {name:?}"))?;
+        let mut addresses = Vec::new();
+        for record in records.iter() {
+            let target = record.target().to_utf8();
+            let ips = match self.lookup_ip(&target).await {
+                Ok(ips) => ips,
+                Err(err) => {
+                    debug!(?err, target, "SRV target does not resolve");
+                    continue;
+                }
+            };
+            let host = format!("{}:{}", target.trim_end_matches('.'), record.port());
+            addresses.extend(ips.into_iter().map(|ip| ResolvedAddress {
+                host: host.clone(),
+                addr: SocketAddr::new(ip, record.port()),
+                priority: record.priority(),
+                weight: record.weight(),
+            }));
+        }
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, Ipv6Addr},
+        str::FromStr,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use hickory_resolver::{
+        Name,
+        proto::{
+            op::{Message, MessageType, ResponseCode},
+            rr::{
+                RData, Record, RecordType,
+                rdata::{A, AAAA, SOA, SRV},
+            },
+        },
+    };
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Minimal DNS server answering from a fixed set of records
+    struct StubServer {
+        addr: SocketAddr,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl StubServer {
+        async fn start(records: Vec<Record>) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let queries = Arc::new(AtomicUsize::new(0));
+
+            let mut zone: HashMap<(Name, RecordType), Vec<Record>> = HashMap::new();
+            for record in records {
+                zone.entry((record.name().clone(), record.record_type()))
+                    .or_default()
+                    .push(record);
+            }
+
+            let counter = Arc::clone(&queries);
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+                    let request = Message::from_vec(&buffer[..len]).unwrap();
+                    counter.fetch_add(1, Ordering::Relaxed);
+
+                    let mut response = Message::new();
+                    response
+                        .set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .set_authoritative(true)
+                        .set_recursion_available(true);
+                    for query in request.queries() {
+                        response.add_query(query.clone());
+                        match zone.get(&(query.name().clone(), query.query_type())) {
+                            Some(records) => {
+                                response.add_answers(records.iter().cloned());
+                            }
+                            None => {
+                                response.set_response_code(ResponseCode::NXDomain);
+                                response.add_name_server(Record::from_rdata(
+                                    Name::from_str("internal.").unwrap(),
+                                    60,
+                                    RData::SOA(SOA::new(
+                                        Name::from_str("ns.internal.").unwrap(),
+                                        Name::from_str("hostmaster.internal.").unwrap(),
+                                        1,
+                                        3600,
+                                        600,
+                                        86400,
+                                        60,
+                                    )),
+                                ));
+                            }
+                        }
+                    }
+                    let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+                }
+            });
+
+            Self { addr, queries }
+        }
+
+        fn resolver(&self) -> DnsResolver {
+            DnsResolver::new(&DnsConfig {
+                nameservers: vec![self.addr],
+                ..toml::from_str("").unwrap()
+            })
+            .unwrap()
+        }
+
+        fn queries(&self) -> usize {
+            self.queries.load(Ordering::Relaxed)
+        }
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_srv_backend() {
+        let server = StubServer::start(vec![
+            Record::from_rdata(
+                name("_https._tcp.service.internal."),
+                300,
+                RData::SRV(SRV::new(10, 5, 8443, name("a.service.internal."))),
+            ),
+            Record::from_rdata(
+                name("_https._tcp.service.internal."),
+                300,
+                RData::SRV(SRV::new(20, 1, 443, name("b.service.internal."))),
+            ),
+            Record::from_rdata(
+                name("a.service.internal."),
+                300,
+                RData::AAAA(AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+            ),
+            Record::from_rdata(
+                name("b.service.internal."),
+                300,
+                RData::A(A(Ipv4Addr::new(192, 0, 2, 1))),
+            ),
+        ])
+        .await;
+        let resolver = server.resolver();
+
+        let mut addresses = resolver
+            .resolve_backend("_https._tcp.service.internal.")
+            .await
+            .unwrap();
+        addresses.sort_by_key(|address| address.priority);
+        assert_eq!(
+            addresses,
+            [
+                ResolvedAddress {
+                    host: "a.service.internal:8443".to_string(),
+                    addr: "[2001:db8::1]:8443".parse().unwrap(),
+                    priority: 10,
+                    weight: 5,
+                },
+                ResolvedAddress {
+                    host: "b.service.internal:443".to_string(),
+                    addr: "192.0.2.1:443".parse().unwrap(),
+                    priority: 20,
+                    weight: 1,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let server = StubServer::start(vec![Record::from_rdata(
+            name("backend.internal."),
+            300,
+            RData::A(A(Ipv4Addr::new(192, 0, 2, 1))),
+        )])
+        .await;
+        let resolver = server.resolver();
+
+        let resolve = || resolver.resolve_backend("backend.internal.:443");
+        assert_eq!(
+            resolve().await.unwrap()[0].addr,
+            "192.0.2.1:443".parse().unwrap()
+        );
+        let queries = server.queries();
+        assert_eq!(
+            resolve().await.unwrap()[0].addr,
+            "192.0.2.1:443".parse().unwrap()
+        );
+        assert_eq!(server.queries(), queries);
+
+        // negative responses are cached as well
+        assert!(resolver.lookup_ip("missing.internal.").await.is_err());
+        let queries = server.queries();
+        assert!(resolver.lookup_ip("missing.internal.").await.is_err());
+        assert_eq!(server.queries(), queries);
+    }
+
+    #[tokio::test]
+    async fn test_literal_addresses() {
+        let resolver = DnsResolver::new(&DnsConfig {
+            nameservers: vec!["127.0.0.1:9".parse().unwrap()],
+            ..toml::from_str("").unwrap()
+        })
+        .unwrap();
+        assert_eq!(
+            resolver.resolve_backend("[2001:db8::1]:443").await.unwrap()[0].addr,
+            "[2001:db8::1]:443".parse().unwrap()
+        );
+        assert_eq!(
+            resolver.lookup_ip("192.0.2.1").await.unwrap(),
+            [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use crate::{
+    config::{AddressFamily, Backend},
+    dns::DnsResolver,
+    happy_eyeballs,
+    routing::sni_matches,
+    socket::{Connector, local_addresses},
+};
+
+/// Connects to whatever the SNI of a client resolves to
+pub struct DynamicForwarder {
+    domains: Vec<String>,
+    address_family: AddressFamily,
+    port: Option<u16>,
+    allow_private_addresses: bool,
+    attempt_delay: Duration,
+    /// Addresses the frontends listen on, never connected to
+    self_addresses: Vec<IpAddr>,
+    resolver: Arc<DnsResolver>,
+}
+
+impl DynamicForwarder {
+    pub fn new(
+        config: &Backend,
+        self_addresses: Vec<IpAddr>,
+        resolver: Arc<DnsResolver>,
+    ) -> Result<Self> {
+        if config.domains.is_empty() {
+            bail!("dynamic backends need a list of allowed domains");
+        }
+        Ok(Self {
+            domains: config.domains.clone(),
+            address_family: config.address_family,
+            port: config.port,
+            allow_private_addresses: config.allow_private_addresses,
+            attempt_delay: Duration::from_millis(config.happy_eyeballs_delay_ms),
+            self_addresses,
+            resolver,
+        })
+    }
+
+    /// Resolves the SNI and connects to one of its allowed addresses
+    ///
+    /// `local_addr` is the address the client connected to.
+    /// Its port is used if no port is configured.
+    pub async fn connect(
+        &self,
+        sni: &str,
+        local_addr: SocketAddr,
+        connector: &Connector,
+    ) -> Result<(TcpStream, SocketAddr)> {
+        if !self.domains.iter().any(|pattern| sni_matches(pattern, sni)) {
+            bail!("{sni:?} is not an allowed domain");
+        }
+        let port = self.port.unwrap_or(local_addr.port());
+        // frontends listening on a wildcard address accept connections to every address of the host
+        let host_addresses: Vec<_> = local_addresses()
+            .context("failed listing the addresses of the host")?
+            .into_iter()
+            .map(|ip| ip.to_canonical())
+            .collect();
+
+        let resolved: Vec<_> = self
+            .resolver
+            .lookup_ip(sni)
+            .await
+            .with_context(|| format!("no DNS entry for {sni:?}"))?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        let addresses: Vec<_> = resolved
+            .iter()
+            .copied()
+            .filter(|addr| self.address_family.allows(addr.ip()))
+            .filter(|addr| {
+                let allowed = self.is_allowed_target(addr.ip(), local_addr.ip(), &host_addresses);
+                if !allowed {
+                    debug!(%addr, sni, "refusing to connect to forbidden address");
+                }
+                allowed
+            })
+            .collect();
+        if addresses.is_empty() {
+            bail!("{sni:?} has no allowed addresses in {resolved:?}");
+        }
+
+        let addresses = happy_eyeballs::interleave(&addresses, self.address_family.prefers_ipv6());
+        let (stream, addr) = happy_eyeballs::connect(&addresses, self.attempt_delay, |addr| {
+            connector.connect(addr)
+        })
+        .await?;
+        info!(sni, %addr, "connected to dynamic backend");
+        Ok((stream, addr))
+    }
+
+    fn is_allowed_target(&self, ip: IpAddr, local_ip: IpAddr, host_addresses: &[IpAddr]) -> bool {
+        let ip = ip.to_canonical();
+        // connecting to the unspecified address reaches the host itself
+        let is_host = ip.is_loopback()
+            || ip.is_unspecified()
+            || ip == local_ip.to_canonical()
+            || self.self_addresses.contains(&ip)
+            || host_addresses.contains(&ip);
+        !is_host && (self.allow_private_addresses || is_public(ip))
+    }
+}
+
+/// Checks if an address is globally reachable
+///
+/// Loopback, private, link local, shared, documentation, multicast and reserved addresses are not.
+/// Addresses of the NAT64 well-known prefix, 6to4 and Teredo are checked by the embedded IPv4 addresses.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // 0.0.0.0/8
+        || a == 0
+        // shared address space 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // NAT64 well-known prefix 64:ff9b::/96, the local-use prefix 64:ff9b:1::/48 is checked below
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    // 6to4 2002::/16, the IPv4 address follows the prefix
+    if segments[0] == 0x2002 {
+        let [_, _, a, b, c, d, ..] = ip.octets();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    // Teredo 2001::/32, with the IPv4 addresses of the server and the inverted one of the client
+    if segments[..2] == [0x2001, 0] {
+        let [.., a, b, c, d, _, _, _, _, e, f, g, h] = ip.octets();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d)) && is_public_v4(!Ipv4Addr::new(e, f, g, h));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // deprecated site local fec0::/10
+        || (segments[0] & 0xffc0) == 0xfec0
+        // IETF protocol assignments 2001::/23, including benchmarking 2001:2::/48
+        || (segments[0] == 0x2001 && segments[1] < 0x200)
+        // local-use NAT64 64:ff9b:1::/48
+        || segments[..3] == [0x64, 0xff9b, 1]
+        // documentation 2001:db8::/32
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // discard-only 100::/64
+        || segments[..4] == [0x100, 0, 0, 0]
+        // IPv4-compatible and other addresses in ::/8
+        || (segments[0] & 0xff00) == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver() -> Arc<DnsResolver> {
+        Arc::new(
+            DnsResolver::new(&toml::from_str(r#"nameservers = ["127.0.0.1:9"]"#).unwrap()).unwrap(),
+        )
+    }
+
+    fn forwarder(config: &str) -> DynamicForwarder {
+        let backend: Backend = toml::from_str(config).unwrap();
+        DynamicForwarder::new(&backend, vec!["2001:db8::443".parse().unwrap()], resolver()).unwrap()
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+            "2001:db8::1",
+            "64:ff9b::10.0.0.1",
+            // 6to4 of 127.0.0.1 and 192.168.1.1
+            "2002:7f00:1::1",
+            "2002:c0a8:101::1",
+            // Teredo with the server 10.0.0.1 or the client 127.0.0.1
+            "2001:0:a00:1::f7ff:fffe",
+            "2001:0:808:808::80ff:fffe",
+            "fec0::1",
+            "feff::1",
+            "2001:2::1",
+            "2001:1::1",
+            "2001:1ff:ffff::1",
+            "64:ff9b:1::a00:1",
+            "64:ff9b:1::808:808",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} should not be public");
+        }
+        for ip in [
+            "1.1.1.1",
+            "2606:4700::1111",
+            "64:ff9b::1.1.1.1",
+            "2002:808:808::1",
+            "2001:0:808:808::f7f7:f7f7",
+            "::ffff:8.8.8.8",
+            "2001:200::1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} should be public");
+        }
+    }
+
+    #[test]
+    fn test_allowed_target() {
+        let public_only = forwarder(
+            r#"
+            type = "dynamic"
+            domains = ["*.example.com"]
+            "#,
+        );
+        let local_ip = "2606:4700::2".parse().unwrap();
+        let host_addresses = ["2606:4700::2".parse().unwrap(), "1.1.1.2".parse().unwrap()];
+        assert!(public_only.is_allowed_target("2606:4700::1".parse().unwrap(), local_ip, &[]));
+        assert!(!public_only.is_allowed_target(local_ip, local_ip, &[]));
+        assert!(!public_only.is_allowed_target("127.0.0.1".parse().unwrap(), local_ip, &[]));
+        // another address of the host, reachable through a wildcard listener
+        assert!(!public_only.is_allowed_target(
+            "::ffff:1.1.1.2".parse().unwrap(),
+            local_ip,
+            &host_addresses
+        ));
+
+        let private = forwarder(
+            r#"
+            type = "dynamic"
+            domains = ["*.example.com"]
+            allow-private-addresses = true
+            "#,
+        );
+        assert!(private.is_allowed_target("10.0.0.1".parse().unwrap(), local_ip, &[]));
+        // the address of a frontend
+        assert!(!private.is_allowed_target("2001:db8::443".parse().unwrap(), local_ip, &[]));
+        // the host itself, even if loopback addresses are allowed otherwise
+        for ip in ["127.0.0.2", "::1", "0.0.0.0", "::"] {
+            assert!(
+                !private.is_allowed_target(ip.parse().unwrap(), local_ip, &[]),
+                "{ip}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_domain_allowlist() {
+        let forwarder = forwarder(
+            r#"
+            type = "dynamic"
+            domains = ["*.example.com"]
+            "#,
+        );
+        let local_addr = "[2606:4700::2]:443".parse().unwrap();
+        let err = forwarder
+            .connect("example.org", local_addr, &Connector::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not an allowed domain"));
+    }
+
+    #[test]
+    fn test_domains_are_mandatory() {
+        let backend: Backend = toml::from_str(r#"type = "dynamic""#).unwrap();
+        assert!(DynamicForwarder::new(&backend, Vec::new(), resolver()).is_err());
+    }
+}
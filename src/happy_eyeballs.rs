@@ -0,0 +1,154 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use futures::{StreamExt, stream::FuturesUnordered};
+use tokio::{net::TcpStream, time::sleep_until};
+use tracing::debug;
+
+/// Orders addresses alternating between the address families, starting with the preferred one
+///
+/// The order within a family is kept.
+pub fn interleave(addresses: &[SocketAddr], prefer_ipv6: bool) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .iter()
+        .partition(|addr| addr.is_ipv6() == prefer_ipv6);
+    let mut interleaved = Vec::with_capacity(addresses.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Connects to the first address that answers, starting a new attempt every `attempt_delay`
+///
+/// A failed attempt starts the next one immediately.
+/// Returns the connection and the address that won.
+pub async fn connect<F, Fut>(
+    addresses: &[SocketAddr],
+    attempt_delay: Duration,
+    connect: F,
+) -> Result<(TcpStream, SocketAddr)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<TcpStream>>,
+{
+    let mut remaining = addresses.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let attempt = |addr: SocketAddr| {
+        let connection = connect(addr);
+        async move { (addr, connection.await) }
+    };
+    let mut last_error: Option<io::Error> = None;
+
+    let mut next_attempt = Instant::now();
+    loop {
+        if attempts.is_empty() || Instant::now() >= next_attempt {
+            match remaining.next() {
+                Some(addr) => {
+                    debug!(%addr, "starting connection attempt");
+                    attempts.push(attempt(addr));
+                    next_attempt = Instant::now() + attempt_delay;
+                }
+                None if attempts.is_empty() => {
+                    return Err(match last_error {
+                        Some(err) => anyhow::Error::from(err),
+                        None => anyhow::anyhow!("no addresses to connect to"),
+                    })
+                    .context("all connection attempts failed");
+                }
+                None => {}
+            }
+        }
+
+        let timer = pin!(sleep_until(next_attempt.into()));
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(err) => {
+                    debug!(%addr, ?err, "connection attempt failed");
+                    last_error = Some(err);
+                    // start the next attempt right away
+                    next_attempt = Instant::now();
+                }
+            },
+            () = timer, if remaining.len() > 0 => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let addresses: Vec<SocketAddr> = [
+            "192.0.2.1:443",
+            "192.0.2.2:443",
+            "192.0.2.3:443",
+            "[2001:db8::1]:443",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let order = |prefer_ipv6| {
+            interleave(&addresses, prefer_ipv6)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            order(true),
+            [
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443"
+            ]
+        );
+        assert_eq!(
+            order(false),
+            [
+                "192.0.2.1:443",
+                "[2001:db8::1]:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_attempt_starts_next() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let start = Instant::now();
+        let (_stream, addr) = connect(
+            &[closed, listener.local_addr().unwrap()],
+            Duration::from_secs(10),
+            TcpStream::connect,
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, listener.local_addr().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let delay = Duration::from_secs(10);
+        assert!(connect(&[closed], delay, TcpStream::connect).await.is_err());
+        assert!(connect(&[], delay, TcpStream::connect).await.is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::str;
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::RedirectStatus;
+
+/// Path prefix of HTTP-01 challenges of ACME (RFC 8555)
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// The parts of an HTTP/1.x request head needed for routing
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestHead<'a> {
+    pub method: &'a str,
+    /// Path and query, also for requests in absolute form
+    pub path: &'a str,
+    /// Host without port, lowercase
+    pub host: Option<String>,
+}
+
+/// Errors answered with a status code instead of silently closing the connection
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HeadError {
+    /// 431 Request Header Fields Too Large
+    TooLarge,
+    /// 400 Bad Request
+    Malformed,
+}
+
+impl HeadError {
+    pub fn response(self) -> &'static [u8] {
+        match self {
+            Self::TooLarge => {
+                b"HTTP/1.1 431 Request Header Fields Too Large\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            }
+            Self::Malformed => {
+                b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            }
+        }
+    }
+}
+
+pub const NOT_FOUND_RESPONSE: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+/// Reads from the client until the end of the request head
+///
+/// The returned buffer contains everything read, which can include the start of the body.
+/// Returns `Ok(Err(_))` if the head is larger than `max_size` or the client closed the connection.
+pub async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_size: usize,
+) -> Result<Result<Vec<u8>, HeadError>> {
+    let mut buffer = Vec::with_capacity(max_size.min(4096));
+    let mut chunk = [0u8; 4096];
+    loop {
+        let len = stream
+            .read(&mut chunk)
+            .await
+            .context("failed reading HTTP request head")?;
+        if len == 0 {
+            return Ok(Err(HeadError::Malformed));
+        }
+        // the end of the head may span two reads
+        let search_start = buffer.len().saturating_sub(3);
+        buffer.extend_from_slice(&chunk[..len]);
+        if let Some(end) = find_head_end(&buffer[search_start..]) {
+            if search_start + end > max_size {
+                return Ok(Err(HeadError::TooLarge));
+            }
+            return Ok(Ok(buffer));
+        }
+        if buffer.len() > max_size {
+            return Ok(Err(HeadError::TooLarge));
+        }
+    }
+}
+
+/// Offset after the empty line ending the head
+pub fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+/// Parses the request line and the `Host` header
+pub fn parse_head(buffer: &[u8]) -> Result<RequestHead<'_>> {
+    let end = find_head_end(buffer).context("incomplete request head")?;
+    let head = str::from_utf8(&buffer[..end]).context("request head is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!("invalid request line {request_line:?}");
+    };
+    if !version.starts_with("HTTP/1.") {
+        bail!("unsupported HTTP version {version:?}");
+    }
+    // lines are split at CRLF only, a bare LF would end up in responses like redirects
+    if target.contains(|c: char| c.is_ascii_control()) {
+        bail!("control character in request target {target:?}");
+    }
+
+    // absolute form like http://example.com/path takes precedence over the Host header
+    let (authority, path) = match target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+    {
+        Some(rest) => match rest.find('/') {
+            Some(slash) => (Some(&rest[..slash]), &rest[slash..]),
+            None => (Some(rest), "/"),
+        },
+        None => (None, target),
+    };
+
+    let mut host_header = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("invalid header line {line:?}"))?;
+        if name.eq_ignore_ascii_case("host") {
+            if host_header.is_some() {
+                bail!("multiple Host headers");
+            }
+            let value = value.trim();
+            if value.contains(|c: char| c.is_ascii_control()) {
+                bail!("control character in Host header {value:?}");
+            }
+            host_header = Some(value);
+        }
+    }
+
+    Ok(RequestHead {
+        method,
+        path,
+        host: authority.or(host_header).map(strip_port),
+    })
+}
+
+/// Removes the port of a `Host` header value, keeping IPv6 literals in brackets
+fn strip_port(host: &str) -> String {
+    let host = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Response redirecting the client to the same URL with HTTPS
+pub fn redirect_response(status: RedirectStatus, host: &str, path: &str) -> Vec<u8> {
+    let status_line = match status {
+        RedirectStatus::MovedPermanently => "301 Moved Permanently",
+        RedirectStatus::PermanentRedirect => "308 Permanent Redirect",
+    };
+    format!(
+        "HTTP/1.1 {status_line}\r\nlocation: https://{host}{path}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        let head = parse_head(
+            b"GET /index.html?q=1 HTTP/1.1\r\nUser-Agent: test\r\nHOST: Example.com:8080\r\n\r\nbody",
+        )
+        .unwrap();
+        assert_eq!(
+            head,
+            RequestHead {
+                method: "GET",
+                path: "/index.html?q=1",
+                host: Some("example.com".to_string()),
+            }
+        );
+
+        let absolute =
+            parse_head(b"GET http://[2001:db8::1]:80/x HTTP/1.1\r\nHost: other\r\n\r\n").unwrap();
+        assert_eq!(absolute.host.as_deref(), Some("[2001:db8::1]"));
+        assert_eq!(absolute.path, "/x");
+
+        let http10 = parse_head(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(http10.host, None);
+
+        assert!(parse_head(b"GET / HTTP/2\r\n\r\n").is_err());
+        assert!(parse_head(b"GET /\r\n\r\n").is_err());
+        assert!(parse_head(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_head_control_characters() {
+        for request in [
+            &b"GET / HTTP/1.1\r\nHost: a\nSet-Cookie: x=y\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\nHost: a\x7f\r\n\r\n",
+            b"GET /\nSet-Cookie:x=y HTTP/1.1\r\nHost: a\r\n\r\n",
+            b"GET http://a\x00/ HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(parse_head(request).is_err(), "{request:?}");
+        }
+        // tabs around the value are optional whitespace
+        let head = parse_head(b"GET / HTTP/1.1\r\nHost:\ta\t\r\n\r\n").unwrap();
+        assert_eq!(head.host.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn test_read_head() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            client
+                .write_all(b"POST / HTTP/1.1\r\nHost: a\r")
+                .await
+                .unwrap();
+            client.write_all(b"\n\r\nbody").await.unwrap();
+        });
+        let buffer = read_head(&mut server, 1024).await.unwrap().unwrap();
+        assert_eq!(buffer, b"POST / HTTP/1.1\r\nHost: a\r\n\r\nbody");
+
+        let data = [
+            b"GET / HTTP/1.1\r\nX: ".as_slice(),
+            &[b'a'; 100],
+            b"\r\n\r\n",
+        ]
+        .concat();
+        assert_eq!(
+            read_head(&mut data.as_slice(), 64).await.unwrap(),
+            Err(HeadError::TooLarge)
+        );
+        assert_eq!(
+            read_head(&mut b"GET / HTTP/1.1\r\n".as_slice(), 64)
+                .await
+                .unwrap(),
+            Err(HeadError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_redirect() {
+        assert_eq!(
+            str::from_utf8(&redirect_response(
+                RedirectStatus::PermanentRedirect,
+                "example.com",
+                "/a?b"
+            ))
+            .unwrap(),
+            "HTTP/1.1 308 Permanent Redirect\r\nlocation: https://example.com/a?b\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+        );
+    }
+}
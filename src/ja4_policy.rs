@@ -0,0 +1,269 @@
+use std::{fs, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use parking_lot::RwLock;
+use tracing::{error, info};
+
+use crate::{
+    access::close_denied,
+    config::{Ja4PolicyConfig, TlsAlert},
+    reload::{FileWatch, Reload},
+    stream::Stream,
+};
+
+/// Allow, deny and tag rules for JA4 fingerprints
+pub struct Ja4Policy {
+    config: Ja4PolicyConfig,
+    files: FileWatch,
+    rules: RwLock<Arc<Vec<Ja4Rule>>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Ja4Rule {
+    action: Ja4RuleAction,
+    pattern: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Ja4RuleAction {
+    Allow,
+    Deny,
+    Suspicious,
+    Tag(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Ja4Action {
+    #[default]
+    Allow,
+    Deny,
+    /// Route to the suspicious backend
+    Suspicious,
+}
+
+/// Result of evaluating a [`Ja4Policy`]
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct Ja4Verdict {
+    pub action: Ja4Action,
+    /// Tags of all matching `tag` rules before the deciding rule
+    pub tags: Vec<String>,
+}
+
+impl Ja4Policy {
+    pub fn new(config: Ja4PolicyConfig) -> Result<Self> {
+        let files = FileWatch::new(config.files.clone());
+        let rules = load_rules(&config)?;
+        Ok(Self {
+            config,
+            files,
+            rules: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    pub const fn config(&self) -> &Ja4PolicyConfig {
+        &self.config
+    }
+
+    /// Applies the rules in order
+    ///
+    /// `tag` rules collect tags, the first other matching rule decides.
+    /// If no rule decides, the client is allowed.
+    pub fn evaluate(&self, ja4: &str) -> Ja4Verdict {
+        let rules = Arc::clone(&self.rules.read());
+        let mut verdict = Ja4Verdict::default();
+        for rule in rules.iter().filter(|rule| ja4_matches(&rule.pattern, ja4)) {
+            verdict.action = match &rule.action {
+                Ja4RuleAction::Tag(tag) => {
+                    if !verdict.tags.contains(tag) {
+                        verdict.tags.push(tag.clone());
+                    }
+                    continue;
+                }
+                Ja4RuleAction::Allow => Ja4Action::Allow,
+                Ja4RuleAction::Deny => Ja4Action::Deny,
+                Ja4RuleAction::Suspicious => Ja4Action::Suspicious,
+            };
+            break;
+        }
+        verdict
+    }
+
+    /// Closes the connection of a denied client as configured
+    pub async fn deny(&self, stream: Stream) -> Result<()> {
+        close_denied(
+            stream,
+            self.config.deny_action,
+            self.config.alert.unwrap_or(TlsAlert::HandshakeFailure),
+        )
+        .await
+    }
+}
+
+impl Reload for Ja4Policy {
+    fn reload_if_changed(&self) {
+        if !self.files.changed() {
+            return;
+        }
+        match load_rules(&self.config) {
+            Ok(rules) => {
+                info!(files = ?self.files.paths(), "reloaded JA4 policy");
+                *self.rules.write() = Arc::new(rules);
+            }
+            Err(err) => error!(?err, "failed reloading JA4 policy"),
+        }
+    }
+}
+
+fn load_rules(config: &Ja4PolicyConfig) -> Result<Vec<Ja4Rule>> {
+    let mut rules = Vec::new();
+    for rule in &config.rules {
+        rules.push(parse_rule(rule)?);
+    }
+    for path in &config.files {
+        let content = fs::read_to_string(path).with_context(|| format!("can't read {path:?}"))?;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                rules.push(parse_rule(line).with_context(|| format!("in {path:?}"))?);
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// Parses rules like `deny t13d1516h2_*` or `tag scraper t13d*`
+fn parse_rule(line: &str) -> Result<Ja4Rule> {
+    let mut words = line.split_whitespace();
+    let action = match words.next() {
+        Some("allow") => Ja4RuleAction::Allow,
+        Some("deny") => Ja4RuleAction::Deny,
+        Some("suspicious") => Ja4RuleAction::Suspicious,
+        Some("tag") => Ja4RuleAction::Tag(
+            words
+                .next()
+                .with_context(|| format!("tag rule {line:?} has no tag"))?
+                .to_string(),
+        ),
+        _ => bail!("JA4 rule {line:?} has an unknown action"),
+    };
+    let pattern = words
+        .next()
+        .with_context(|| format!("JA4 rule {line:?} has no pattern"))?
+        .to_string();
+    if words.next().is_some() {
+        bail!("JA4 rule {line:?} has trailing data");
+    }
+    Ok(Ja4Rule { action, pattern })
+}
+
+/// Matches a JA4 fingerprint against a pattern
+///
+/// The pattern is split into the `a_b_c` sections of the fingerprint and each section is matched
+/// as a glob, where `*` matches any number and `?` exactly one character.
+/// Sections that are missing in the pattern match everything,
+/// so `t13d*` matches all TLS 1.3 clients with SNI.
+pub fn ja4_matches(pattern: &str, ja4: &str) -> bool {
+    let mut sections = ja4.split('_');
+    pattern.split('_').all(|pattern| {
+        sections
+            .next()
+            .is_some_and(|section| glob_matches(pattern, section))
+    })
+}
+
+fn glob_matches(pattern: &str, input: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let input = input.as_bytes();
+    let (mut p, mut i) = (0, 0);
+    // position of the last `*` in the pattern and the input position it was tried at
+    let mut backtrack = None;
+    while i < input.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == input[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            i = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "t13d1516h2_8daaf6152771_02713d6af862";
+    const CURL: &str = "t13d3012h2_1d37bd780c83_882d495ac381";
+
+    #[test]
+    fn test_ja4_matches() {
+        assert!(ja4_matches(CHROME, CHROME));
+        assert!(!ja4_matches(CHROME, CURL));
+        assert!(ja4_matches("*", CHROME));
+        assert!(ja4_matches("t13d*", CHROME));
+        assert!(ja4_matches("t13d1516h2_8daaf6152771", CHROME));
+        assert!(ja4_matches("t13d15??h2_*_02713d6af862", CHROME));
+        assert!(!ja4_matches("t12d*", CHROME));
+        // `*` does not cross section borders
+        assert!(!ja4_matches("t13d*_02713d6af862", CHROME));
+        assert!(!ja4_matches(
+            "t13d1516h2_8daaf6152771_02713d6af862_x",
+            CHROME
+        ));
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("", ""));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*c", "abbbc"));
+        assert!(glob_matches("a*b*c", "axbxbxc"));
+        assert!(!glob_matches("a*c", "abbb"));
+        assert!(!glob_matches("abc", "abcd"));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy = Ja4Policy::new(Ja4PolicyConfig {
+            rules: vec![
+                "tag tls13 t13*".to_string(),
+                "allow t13d1516h2_8daaf6152771_02713d6af862".to_string(),
+                "tag scraper t13d3012h2_1d37bd780c83".to_string(),
+                "suspicious t13d3012h2".to_string(),
+            ],
+            ..toml::from_str("").unwrap()
+        })
+        .unwrap();
+
+        assert_eq!(
+            policy.evaluate(CHROME),
+            Ja4Verdict {
+                action: Ja4Action::Allow,
+                tags: vec!["tls13".to_string()]
+            }
+        );
+        assert_eq!(
+            policy.evaluate(CURL),
+            Ja4Verdict {
+                action: Ja4Action::Suspicious,
+                tags: vec!["tls13".to_string(), "scraper".to_string()]
+            }
+        );
+        assert_eq!(policy.evaluate("t12d1516h2_x_y"), Ja4Verdict::default());
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert!(parse_rule("deny t13d*").is_ok());
+        assert!(parse_rule("block t13d*").is_err());
+        assert!(parse_rule("tag t13d*").is_err());
+        assert!(parse_rule("deny t13d* t12d*").is_err());
+    }
+}
@@ -1,26 +1,70 @@
+mod access;
+mod bandwidth;
 mod config;
+mod dns;
+mod dynamic;
+mod ech;
+mod happy_eyeballs;
+mod http;
+mod ja4_policy;
+mod metrics;
+mod preconnect;
+mod proxy_protocol;
+mod quic;
+mod rate_limit;
+mod reload;
+mod route_test;
+mod routing;
+mod socket;
+mod starttls;
 mod state;
+mod stream;
+mod tunnel;
+mod udp;
 
 use std::{
     fs,
-    net::{SocketAddr, SocketAddrV6},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, bail};
 use clap::Parser;
+use futures::future::try_join_all;
 use mimalloc::MiMalloc;
-use tls_client_hello_parser::{ClientHello, Ja4Fingerprint};
-use tlslb::cli::Cli;
+use tls_client_hello_parser::{
+    AlertDescription, AlertLevel, ClientHello, EncryptedClientHello, Ja4Fingerprint, TlsParseError,
+    encode_alert,
+};
+use tlslb::cli::{Cli, Command};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, copy},
-    net::{TcpListener, TcpStream, lookup_host},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy, copy_bidirectional},
+    net::UdpSocket,
     spawn, try_join,
 };
-use tracing::{Level, info, instrument};
+use tracing::{Level, debug, error, info, instrument};
+
+use crate::{
+    bandwidth::{Throttle, ThrottledStream},
+    config::{Config, FrontendKind, ProxyProtocolVersion, RouteAction, TlsAlert},
+    ja4_policy::{Ja4Action, Ja4Policy},
+    proxy_protocol::{PP2_TYPE_AUTHORITY, PP2_TYPE_TLSLB_TAGS},
+    routing::{CompiledRoute, RouteInput},
+    socket::Listener,
+    starttls::{Preamble, Protocol},
+    state::{ConnectionRef, Pool, State},
+    stream::{PrefixedStream, Stream},
+    udp::QuicForwarder,
+};
+
+/// Wire version of TLS 1.0, older versions are SSL
+const TLS_1_0: u16 = 0x0301;
+
+const TLS_RECORD_HEADER_LEN: usize = 5;
 
-use crate::{config::Config, state::State};
+/// Client hellos are not read beyond this length
+const MAX_CLIENT_HELLO_LEN: usize = 16384;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -36,80 +80,765 @@ async fn main() -> Result<()> {
 
     let config: Config = toml::from_str(&fs::read_to_string(&opts.config_file)?)?;
 
+    if let Some(Command::RouteTest(args)) = &opts.command {
+        return route_test::run(&config, args);
+    }
+    if let Some(Command::EchConfig(args)) = &opts.command {
+        return ech::print_config_list(&config, args);
+    }
+
     info!("{config:?}");
 
     let config = Arc::new(config);
     let state = Arc::new(State::new(Arc::clone(&config)).await?);
 
-    let sock_addr: SocketAddr = config.frontends.https.listen_address;
-    let listener = TcpListener::bind(sock_addr)
-        .await
-        .context("failed to bind https socket")?;
-
-    while let Ok((stream, _addr)) = listener.accept().await {
+    if let Some(metrics_address) = config.metrics_address {
+        let listener = metrics::bind(metrics_address).await?;
         let state = Arc::clone(&state);
-        spawn(async move { handle_client_connection(stream, state).await.unwrap() });
+        spawn(async move {
+            if let Err(err) = metrics::serve(listener, state).await {
+                error!(?err, "failed serving metrics");
+            }
+        });
+    }
+
+    let mut listeners = Vec::new();
+    for (name, frontend) in &config.frontends {
+        let name: Arc<str> = Arc::from(name.as_str());
+        if frontend.kind == FrontendKind::Quic {
+            let addr = frontend
+                .listen_address
+                .tcp()
+                .with_context(|| format!("QUIC frontend {name:?} needs an IP address"))?;
+            let socket = UdpSocket::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind socket of frontend {name:?}"))?;
+            let forwarder = QuicForwarder::new(name, socket, Arc::clone(&state));
+            listeners.push(spawn(forwarder.serve()));
+            continue;
+        }
+        for listener in socket::listen(frontend)
+            .with_context(|| format!("failed to bind socket of frontend {name:?}"))?
+        {
+            listeners.push(spawn(accept_connections(
+                Arc::clone(&name),
+                listener,
+                Arc::clone(&state),
+            )));
+        }
     }
+    try_join_all(listeners).await?;
 
     Ok(())
 }
 
-#[instrument(err, skip_all)]
-async fn handle_client_connection(mut client_stream: TcpStream, state: Arc<State>) -> Result<()> {
+async fn accept_connections(frontend: Arc<str>, listener: Listener, state: Arc<State>) {
+    let slots = state.frontends[&*frontend].connection_slots.clone();
+    loop {
+        // stop accepting while the frontend is at max connections
+        let permit = match &slots {
+            Some(slots) => {
+                if slots.available_permits() == 0 {
+                    debug!("frontend is at max connections, pausing accept");
+                }
+                match Arc::clone(slots).acquire_owned().await {
+                    Ok(permit) => Some(permit),
+                    Err(_closed) => return,
+                }
+            }
+            None => None,
+        };
+        let Ok(stream) = listener.accept().await else {
+            return;
+        };
+        let state = Arc::clone(&state);
+        let frontend = Arc::clone(&frontend);
+        spawn(async move {
+            // errors are logged by the instrumentation
+            let _ = handle_client_connection(stream, frontend, state).await;
+            drop(permit);
+        });
+    }
+}
+
+#[instrument(err, skip_all, fields(%frontend))]
+async fn handle_client_connection(
+    client_stream: Stream,
+    frontend: Arc<str>,
+    state: Arc<State>,
+) -> Result<()> {
     let connection_start = Instant::now();
-    let mut buffer = vec![0u8; 16384];
-    let len = client_stream
-        .read(&mut buffer)
+
+    let peer_addr = client_stream.peer_addr()?;
+    // clients of Unix sockets have no IP address
+    let client_ip = peer_addr.map(|addr| addr.ip().to_canonical());
+    let client_asn = client_ip
+        .and_then(|ip| state.ip_to_asn_database.lookup_ip(ip))
+        .map(|v| v.asn());
+
+    let frontend_state = &state.frontends[&*frontend];
+    // access to Unix sockets is controlled by their mode and owner
+    if client_ip.is_some_and(|ip| !frontend_state.access.is_allowed(ip, client_asn)) {
+        info!(
+            ?peer_addr,
+            as_number = client_asn,
+            "denied by frontend access list"
+        );
+        return frontend_state.access.deny(client_stream).await;
+    }
+    let _frontend_permits = match frontend_state
+        .rate_limits
+        .acquire(client_ip, client_asn, None)
+    {
+        Ok(permits) => permits,
+        Err(limiter) => {
+            info!(?peer_addr, key = ?limiter.key(), "rate limited by frontend");
+            return limiter.deny(client_stream).await;
+        }
+    };
+
+    let client = Client {
+        peer_addr,
+        ip: client_ip,
+        asn: client_asn,
+        sni: None,
+        ja4: None,
+        tags: Vec::new(),
+        connection_start,
+    };
+    match state.config.frontends[&*frontend].kind {
+        FrontendKind::Tls => handle_tls(client_stream, &frontend, &state, client, None).await,
+        FrontendKind::Tcp => {
+            let backend = state.config.frontends[&*frontend]
+                .backend
+                .as_ref()
+                .context("TCP frontend has no backend")?;
+            info!(?peer_addr, as_number = client_asn, "got TCP connection");
+            connect_backend(
+                client_stream,
+                &state.pools[backend],
+                &client,
+                None,
+                &[],
+                None,
+            )
+            .await
+        }
+        FrontendKind::Http => handle_http(client_stream, &frontend, &state, client).await,
+        FrontendKind::Quic => bail!("QUIC frontends do not accept TCP connections"),
+        FrontendKind::Smtp => {
+            handle_starttls(client_stream, &frontend, &state, client, Protocol::Smtp).await
+        }
+        FrontendKind::Imap => {
+            handle_starttls(client_stream, &frontend, &state, client, Protocol::Imap).await
+        }
+        FrontendKind::Pop3 => {
+            handle_starttls(client_stream, &frontend, &state, client, Protocol::Pop3).await
+        }
+        FrontendKind::Xmpp => {
+            handle_starttls(client_stream, &frontend, &state, client, Protocol::Xmpp).await
+        }
+        FrontendKind::Postgres => handle_postgres(client_stream, &frontend, &state, client).await,
+        FrontendKind::Connect => {
+            let protocol = tunnel::Protocol::HttpConnect;
+            handle_tunnel(client_stream, &frontend, &state, client, protocol).await
+        }
+        FrontendKind::Socks5 => {
+            let protocol = tunnel::Protocol::Socks5;
+            handle_tunnel(client_stream, &frontend, &state, client, protocol).await
+        }
+    }
+}
+
+/// Routes clients of explicit proxies by the host they ask for
+///
+/// The host is authorized like an SNI before the tunnel is established.
+async fn handle_tunnel(
+    mut client_stream: Stream,
+    frontend: &str,
+    state: &State,
+    client: Client<'_>,
+    protocol: tunnel::Protocol,
+) -> Result<()> {
+    let config = &state.config.frontends[frontend];
+    let Some(mut request) =
+        tunnel::read_request(&mut client_stream, protocol, config.http.max_head_size).await?
+    else {
+        info!(peer_addr = ?client.peer_addr, "invalid tunnel request");
+        return Ok(());
+    };
+    info!(
+        host = request.host,
+        port = request.port,
+        peer_addr = ?client.peer_addr,
+        as_number = client.asn,
+        "got tunnel request"
+    );
+    if !config.tunnel.allowed_ports.contains(&request.port) {
+        info!(port = request.port, "port is not allowed");
+        return request.deny(client_stream).await;
+    }
+
+    let route_input = RouteInput {
+        frontend,
+        sni: Some(&request.host),
+        alpn: &[],
+        tls_version: 0,
+        ja4: "",
+        client_ip: client.ip,
+        client_asn: client.asn,
+    };
+    let route = state.router.route(&route_input);
+    let pool = match route {
+        None => state.select_pool(&request.host, &[]),
+        Some(route) => {
+            info!(route = route.name(), "matched route");
+            match &route.route.action {
+                RouteAction::Forward { backend } | RouteAction::Terminate { backend, .. } => {
+                    Some(&state.pools[backend])
+                }
+                RouteAction::Reject { .. } | RouteAction::Tarpit { .. } => None,
+            }
+        }
+    };
+    let Some(pool) = pool else {
+        info!(host = request.host, "host is not allowed");
+        return request.deny(client_stream).await;
+    };
+    request.accept(&mut client_stream).await?;
+
+    let mut buffer = std::mem::take(&mut request.buffer);
+    if config.tunnel.check_sni {
+        if buffer.is_empty() {
+            buffer = read_client_hello(&mut client_stream).await?;
+        }
+        let tls_client_hello = match ClientHello::try_from(buffer.as_slice()) {
+            Ok(client_hello) => client_hello,
+            Err(err) => {
+                info!(peer_addr = ?client.peer_addr, ?err, "failed parsing TLS client hello");
+                return send_alert(client_stream, config.alerts.malformed_client_hello).await;
+            }
+        };
+        if !request.matches_sni(tls_client_hello.sni()) {
+            info!(
+                sni = tls_client_hello.sni(),
+                host = request.host,
+                "SNI does not match requested host"
+            );
+            return send_alert(client_stream, TlsAlert::HandshakeFailure).await;
+        }
+    }
+
+    let client = Client {
+        sni: Some(&request.host),
+        ..client
+    };
+    connect_backend(client_stream, pool, &client, route, &buffer, None).await
+}
+
+/// Routes PostgreSQL clients by their TLS client hello
+///
+/// Clients of PostgreSQL 17 with `sslnegotiation=direct` start with the client hello.
+/// Both kinds of clients are connected to backends with an `SSLRequest`.
+async fn handle_postgres(
+    client_stream: Stream,
+    frontend: &str,
+    state: &State,
+    client: Client<'_>,
+) -> Result<()> {
+    let mut first = [0u8];
+    if client_stream.peek(&mut first).await? == 0 {
+        return Ok(());
+    }
+    if first == [0x16] {
+        debug!(peer_addr = ?client.peer_addr, "direct TLS PostgreSQL connection");
+        let timeout = state.config.frontends[frontend].starttls.timeout_secs;
+        let preamble = Preamble::postgres_direct(Duration::from_secs(timeout));
+        return handle_tls(client_stream, frontend, state, client, Some(&preamble)).await;
+    }
+    handle_starttls(client_stream, frontend, state, client, Protocol::Postgres).await
+}
+
+/// Answers the plaintext phase of a protocol upgraded with `STARTTLS`,
+/// then routes the client by its TLS client hello
+async fn handle_starttls(
+    mut client_stream: Stream,
+    frontend: &str,
+    state: &State,
+    client: Client<'_>,
+    protocol: Protocol,
+) -> Result<()> {
+    let config = &state.config.frontends[frontend].starttls;
+    let Some(preamble) = starttls::accept(
+        &mut client_stream,
+        protocol,
+        &config.hostname,
+        Duration::from_secs(config.timeout_secs),
+    )
+    .await?
+    else {
+        debug!(peer_addr = ?client.peer_addr, "client quit before starting TLS");
+        return Ok(());
+    };
+    handle_tls(client_stream, frontend, state, client, Some(&preamble)).await
+}
+
+/// Routes HTTP/1.x requests by the `Host` header of the first request
+///
+/// Following requests on the same connection go to the same backend.
+async fn handle_http(
+    mut client_stream: Stream,
+    frontend: &str,
+    state: &State,
+    client: Client<'_>,
+) -> Result<()> {
+    let config = &state.config.frontends[frontend];
+    let buffer = match http::read_head(&mut client_stream, config.http.max_head_size).await? {
+        Ok(buffer) => buffer,
+        Err(err) => {
+            info!(peer_addr = ?client.peer_addr, ?err, "invalid HTTP request");
+            return respond(client_stream, err.response()).await;
+        }
+    };
+    let Ok(head) = http::parse_head(&buffer) else {
+        info!(peer_addr = ?client.peer_addr, "invalid HTTP request");
+        return respond(client_stream, http::HeadError::Malformed.response()).await;
+    };
+    info!(
+        host = head.host,
+        method = head.method,
+        peer_addr = ?client.peer_addr,
+        as_number = client.asn,
+        "got HTTP connection"
+    );
+
+    let acme_backend = config
+        .http
+        .acme_backend
+        .as_ref()
+        .filter(|_| head.path.starts_with(http::ACME_CHALLENGE_PREFIX));
+    let pool = if let Some(backend) = acme_backend {
+        &state.pools[backend]
+    } else {
+        let Some(host) = &head.host else {
+            return respond(client_stream, http::HeadError::Malformed.response()).await;
+        };
+        if let Some(status) = config.http.redirect_to_https {
+            let response = http::redirect_response(status, host, head.path);
+            return respond(client_stream, &response).await;
+        }
+        match state
+            .select_pool(host, &[])
+            .or_else(|| config.backend.as_ref().map(|backend| &state.pools[backend]))
+        {
+            Some(pool) => pool,
+            None => {
+                info!(host, "host is not configured");
+                return respond(client_stream, http::NOT_FOUND_RESPONSE).await;
+            }
+        }
+    };
+
+    let client = Client {
+        sni: head.host.as_deref(),
+        ..client
+    };
+    connect_backend(client_stream, pool, &client, None, &buffer, None).await
+}
+
+/// Sends a complete response and closes the connection
+async fn respond(mut client_stream: Stream, response: &[u8]) -> Result<()> {
+    client_stream
+        .write_all(response)
         .await
-        .context("failed reading TLS header from stream")?;
-    buffer.truncate(len);
-
-    let tls_client_hello =
-        ClientHello::try_from(buffer.as_slice()).context("failed parsing TLS header")?;
-    let sni = tls_client_hello
-        .sni()
-        .context("TLS client hello does not contain SNI")?;
+        .context("failed sending response")?;
+    client_stream.shutdown().await?;
+    Ok(())
+}
+
+/// What is known about a client when it is connected to a backend
+struct Client<'a> {
+    /// `None` for clients of Unix sockets
+    peer_addr: Option<SocketAddr>,
+    ip: Option<IpAddr>,
+    asn: Option<u32>,
+    sni: Option<&'a str>,
+    ja4: Option<&'a str>,
+    /// Tags of the JA4 policies
+    tags: Vec<String>,
+    connection_start: Instant,
+}
+
+/// Routes a client by its TLS client hello
+///
+/// `preamble` is the plaintext phase of clients that started TLS with `STARTTLS`.
+async fn handle_tls(
+    mut client_stream: Stream,
+    frontend: &str,
+    state: &State,
+    client: Client<'_>,
+    preamble: Option<&Preamble>,
+) -> Result<()> {
+    let Client {
+        peer_addr,
+        ip: client_ip,
+        asn: client_asn,
+        connection_start,
+        ..
+    } = client;
+    let frontend_state = &state.frontends[frontend];
+
+    let alerts = &state.config.frontends[frontend].alerts;
+    let buffer = read_client_hello(&mut client_stream).await?;
+    let tls_client_hello = match ClientHello::try_from(buffer.as_slice()) {
+        Ok(client_hello) if client_hello.tls_version() >= TLS_1_0 => client_hello,
+        Ok(_) => {
+            info!(?peer_addr, "client only supports SSL");
+            return send_alert(client_stream, alerts.unsupported_version).await;
+        }
+        Err(err) => {
+            info!(?peer_addr, ?err, "failed parsing TLS client hello");
+            let alert = if is_ssl_record(&buffer) {
+                alerts.unsupported_version
+            } else {
+                alerts.malformed_client_hello
+            };
+            return send_alert(client_stream, alert).await;
+        }
+    };
     let ja4_fingerprint = Ja4Fingerprint::calculate(&tls_client_hello);
-    let peer_addr = client_stream.peer_addr()?;
-    let as_number = state
-        .ip_to_asn_database
-        .lookup_ip(peer_addr.ip())
-        .map(|v| v.asn())
-        .unwrap_or(1337);
+
+    // the outer client hello of ECH only names the public name, routing uses the inner one,
+    // the backend gets the unchanged client hello
+    let inner_buffer = frontend_state
+        .ech
+        .as_ref()
+        .filter(|_| {
+            tls_client_hello
+                .ech()
+                .is_some_and(EncryptedClientHello::is_outer)
+        })
+        .and_then(|keys| keys.decrypt(&buffer));
+    let tls_client_hello = match inner_buffer.as_deref().map(ClientHello::try_from) {
+        Some(Ok(inner)) => {
+            info!(
+                public_name = tls_client_hello.sni(),
+                sni = inner.sni(),
+                "decrypted ECH client hello"
+            );
+            inner
+        }
+        _ => tls_client_hello,
+    };
 
     info!(
-        sni,
+        sni = tls_client_hello.sni(),
         ja4 = ja4_fingerprint.as_ref(),
         ?peer_addr,
-        as_number,
+        as_number = client_asn,
         "got TLS connection"
     );
 
     info!("sni extracted: {:?}", connection_start.elapsed());
 
-    /*
-    let addr = lookup_dns_v6(&sni).await?;
+    let _frontend_ja4_permits = match frontend_state
+        .rate_limits
+        .acquire_ja4(ja4_fingerprint.as_ref())
+    {
+        Ok(permits) => permits,
+        Err(limiter) => {
+            info!(?peer_addr, key = ?limiter.key(), "rate limited by frontend");
+            return limiter.deny(client_stream).await;
+        }
+    };
+
+    let mut ja4_tags = Vec::new();
+    let mut suspicious_pool = None;
+    if let Some(policy) = &frontend_state.ja4_policy {
+        match check_ja4_policy(policy, ja4_fingerprint.as_ref(), &mut ja4_tags) {
+            Some(Ja4Action::Allow) => {}
+            Some(Ja4Action::Suspicious) => {
+                suspicious_pool = policy.config().suspicious_backend.as_ref()
+            }
+            _ => {
+                info!(?peer_addr, "denied by frontend JA4 policy");
+                return policy.deny(client_stream).await;
+            }
+        }
+    }
+
+    let route_input = RouteInput {
+        frontend,
+        sni: tls_client_hello.sni(),
+        alpn: tls_client_hello.alpn(),
+        tls_version: tls_client_hello.tls_version(),
+        ja4: ja4_fingerprint.as_ref(),
+        client_ip,
+        client_asn,
+    };
+
+    let (mut route, mut pool) = if let Some(backend) = suspicious_pool {
+        info!(backend, "routing suspicious client");
+        (None, &state.pools[backend])
+    } else {
+        let route = state.router.route(&route_input);
+        let pool = match route {
+            None => {
+                if let Some(sni) = tls_client_hello.sni() {
+                    let Some(pool) = state.select_pool(sni, tls_client_hello.alpn()) else {
+                        info!(sni, "domain is not configured");
+                        return send_alert(client_stream, alerts.unknown_domain).await;
+                    };
+                    pool
+                } else {
+                    let local_ip = client_stream
+                        .local_addr()?
+                        .map(|addr| addr.ip().to_canonical());
+                    let no_sni = &state.config.frontends[frontend].no_sni;
+                    let Some(backend) = no_sni.backend(local_ip) else {
+                        info!(
+                            ?peer_addr,
+                            ?local_ip,
+                            "TLS client hello does not contain SNI"
+                        );
+                        return send_alert(client_stream, alerts.missing_sni).await;
+                    };
+                    info!(backend, ?local_ip, "routing client without SNI");
+                    &state.pools[backend]
+                }
+            }
+            Some(route) => {
+                info!(route = route.name(), "matched route");
+                match &route.route.action {
+                    RouteAction::Forward { backend } | RouteAction::Terminate { backend, .. } => {
+                        &state.pools[backend]
+                    }
+                    RouteAction::Reject { alert } => {
+                        return send_alert(client_stream, *alert).await;
+                    }
+                    RouteAction::Tarpit { duration_secs } => {
+                        tarpit(client_stream, Duration::from_secs(*duration_secs)).await;
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        (route, pool)
+    };
+
+    if suspicious_pool.is_none()
+        && let Some(policy) = &pool.ja4_policy
+    {
+        match check_ja4_policy(policy, ja4_fingerprint.as_ref(), &mut ja4_tags) {
+            Some(Ja4Action::Allow) => {}
+            Some(Ja4Action::Suspicious) => {
+                let backend = policy
+                    .config()
+                    .suspicious_backend
+                    .as_ref()
+                    .context("JA4 policy has no suspicious backend")?;
+                info!(backend, "routing suspicious client");
+                route = None;
+                pool = &state.pools[backend];
+            }
+            _ => {
+                info!(?peer_addr, "denied by backend JA4 policy");
+                return policy.deny(client_stream).await;
+            }
+        }
+    }
+
+    let client = Client {
+        sni: tls_client_hello.sni(),
+        ja4: Some(ja4_fingerprint.as_ref()),
+        tags: ja4_tags,
+        ..client
+    };
+    connect_backend(client_stream, pool, &client, route, &buffer, preamble).await
+}
+
+/// Reads until the client hello is complete or the client stops sending
+///
+/// The client hello can be larger than one TCP segment,
+/// for example with post-quantum key shares.
+async fn read_client_hello(client_stream: &mut Stream) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let len = client_stream
+            .read(&mut chunk)
+            .await
+            .context("failed reading TLS header from stream")?;
+        buffer.extend_from_slice(&chunk[..len]);
+        let incomplete = buffer.len() < TLS_RECORD_HEADER_LEN
+            || ClientHello::try_from(buffer.as_slice()) == Err(TlsParseError::Incomplete);
+        if len == 0 || !incomplete || buffer.len() >= MAX_CLIENT_HELLO_LEN {
+            return Ok(buffer);
+        }
+    }
+}
 
-    info!("lookup ready: {:?}", connection_start.elapsed());
+/// Whether the data starts like a client hello of SSL 2.0 or a record of SSL 3.0 and older
+fn is_ssl_record(buffer: &[u8]) -> bool {
+    match buffer {
+        // SSL 2.0 header with two length bytes, then the message type client hello
+        [first, _, 1, ..] if first & 0x80 != 0 => true,
+        [0x16, major, minor, ..] => (*major, *minor) <= (3, 0),
+        _ => false,
+    }
+}
 
-    let mut server_stream = TcpStream::connect(addr)
+/// Sends a fatal TLS alert and closes the connection
+async fn send_alert(mut client_stream: Stream, alert: TlsAlert) -> Result<()> {
+    client_stream
+        .write_all(&encode_alert(
+            AlertLevel::Fatal,
+            AlertDescription::from(alert),
+        ))
         .await
-        .with_context(|| format!("can't connect to {sni} on {addr}"))?;
+        .context("failed sending TLS alert")?;
+    client_stream.shutdown().await?;
+    Ok(())
+}
+
+/// Applies the limits of the backend, connects to it and forwards the client
+///
+/// `buffer` is the data already read from the client.
+/// The plaintext phase of `preamble` is repeated to the backend before.
+async fn connect_backend(
+    client_stream: Stream,
+    pool: &Pool,
+    client: &Client<'_>,
+    route: Option<&CompiledRoute>,
+    buffer: &[u8],
+    preamble: Option<&Preamble>,
+) -> Result<()> {
+    let peer_addr = client.peer_addr;
+    if client
+        .ip
+        .is_some_and(|ip| !pool.access.is_allowed(ip, client.asn))
+    {
+        info!(
+            ?peer_addr,
+            as_number = client.asn,
+            "denied by backend access list"
+        );
+        return pool.access.deny(client_stream).await;
+    }
+    let _backend_permits = match pool.rate_limits.acquire(client.ip, client.asn, client.ja4) {
+        Ok(permits) => permits,
+        Err(limiter) => {
+            info!(?peer_addr, key = ?limiter.key(), "rate limited by backend");
+            return limiter.deny(client_stream).await;
+        }
+    };
+
+    let local_addr = client_stream.local_addr()?;
+
+    let _slot = match &pool.queue {
+        Some(queue) => Some(queue.acquire().await?),
+        None => None,
+    };
+
+    let throttle = pool
+        .shaper
+        .as_ref()
+        .map(|shaper| shaper.throttle(client.asn))
+        .unwrap_or_default();
+
+    let proxy_header = match pool.config.proxy_protocol {
+        None => Vec::new(),
+        Some(ProxyProtocolVersion::V2) => {
+            let tags = client.tags.join(",");
+            let mut tlvs = Vec::new();
+            if let Some(sni) = client.sni {
+                tlvs.push((PP2_TYPE_AUTHORITY, sni.as_bytes()));
+            }
+            if !tags.is_empty() {
+                tlvs.push((PP2_TYPE_TLSLB_TAGS, tags.as_bytes()));
+            }
+            proxy_protocol::encode_v2(peer_addr.zip(local_addr), &tlvs)?
+        }
+    };
+
+    let mut server = pool.connect(client.sni, local_addr, peer_addr).await?;
+    let proxy_header = match preamble {
+        None => proxy_header,
+        Some(preamble) => {
+            server
+                .0
+                .write_all(&proxy_header)
+                .await
+                .context("failed sending PROXY header to server")?;
+            let terminating = route.is_some_and(|route| route.acceptor.is_some());
+            preamble.connect(&mut server.0, terminating).await?;
+            Vec::new()
+        }
+    };
+
+    match route {
+        Some(
+            route @ CompiledRoute {
+                acceptor: Some(_), ..
+            },
+        ) => {
+            terminate(
+                client_stream,
+                buffer.to_vec(),
+                &proxy_header,
+                route,
+                server,
+                throttle,
+            )
+            .await
+        }
+        _ => {
+            forward(
+                client_stream,
+                buffer,
+                &proxy_header,
+                server,
+                throttle,
+                client.connection_start,
+            )
+            .await
+        }
+    }
+}
 
-    info!("connected: {:?}", connection_start.elapsed());
-     */
+/// Evaluates a JA4 policy and collects its tags
+///
+/// Returns `None` if the client is suspicious, but there is no backend for suspicious clients.
+fn check_ja4_policy(policy: &Ja4Policy, ja4: &str, tags: &mut Vec<String>) -> Option<Ja4Action> {
+    let verdict = policy.evaluate(ja4);
+    for tag in verdict.tags {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    match verdict.action {
+        Ja4Action::Suspicious => policy
+            .config()
+            .suspicious_backend
+            .as_ref()
+            .map(|_| Ja4Action::Suspicious),
+        action => Some(action),
+    }
+}
 
+/// Forwards the client hello and all following data to a backend connection
+async fn forward(
+    client_stream: Stream,
+    buffer: &[u8],
+    proxy_header: &[u8],
+    (mut server_stream, server_ref): (Stream, ConnectionRef),
+    throttle: Throttle,
+    connection_start: Instant,
+) -> Result<()> {
     let (mut client_read, mut client_write) = client_stream.into_split();
 
-    let (mut server_stream, server_ref) = state
-        .pools
-        .get(sni)
-        .context("domain is not configured")?
-        .get_connection()
-        .await?;
-
     server_stream
-        .write_all(&buffer)
+        .write_all(&[proxy_header, buffer].concat())
         .await
         .context("failed transferring TLS header from client to server")?;
 
@@ -117,7 +846,9 @@ async fn handle_client_connection(mut client_stream: TcpStream, state: Arc<State
 
     //copy_bidirectional(&mut server_stream, &mut client_stream).await?;
 
-    let (mut server_read, mut server_write) = server_stream.into_split();
+    let (server_read, server_write) = server_stream.into_split();
+    let mut server_read = ThrottledStream::new(server_read, throttle.clone());
+    let mut server_write = ThrottledStream::new(server_write, throttle);
 
     let client_read_ref = &mut client_read;
     let client_write_ref = &mut client_write;
@@ -157,21 +888,44 @@ async fn handle_client_connection(mut client_stream: TcpStream, state: Arc<State
     Ok(())
 }
 
-#[instrument(err, ret, level = Level::DEBUG)]
-async fn lookup_dns_v6(sni: &str) -> Result<SocketAddrV6> {
-    info!("looking up");
-    let addrs: Vec<_> = lookup_host(format!("{sni}:443"))
+/// Terminates TLS with the key of the route and forwards the plaintext to a backend connection
+async fn terminate(
+    client_stream: Stream,
+    buffer: Vec<u8>,
+    proxy_header: &[u8],
+    route: &CompiledRoute,
+    (mut server_stream, server_ref): (Stream, ConnectionRef),
+    throttle: Throttle,
+) -> Result<()> {
+    let acceptor = route
+        .acceptor
+        .as_ref()
+        .context("route terminating TLS has no key")?;
+    let mut tls_stream = acceptor
+        .accept(PrefixedStream::new(buffer, client_stream))
         .await
-        .with_context(|| format!("no DNS entry for {sni:?}"))?
-        .collect();
-    info!("{addrs:?}");
-    let addr = addrs
-        .iter()
-        .filter_map(|addr| match addr {
-            SocketAddr::V4(_) => None, // that's me!
-            SocketAddr::V6(addr) => Some(addr),
-        })
-        .next()
-        .with_context(|| format!("{sni} does not have a valid IPv6 address"))?;
-    Ok(*addr)
+        .context("TLS handshake with client failed")?;
+
+    server_stream
+        .write_all(proxy_header)
+        .await
+        .context("failed sending PROXY header to server")?;
+    let mut server_stream = ThrottledStream::new(server_stream, throttle);
+    copy_bidirectional(&mut tls_stream, &mut server_stream)
+        .await
+        .context("failed transferring data between client and server")?;
+    drop(server_ref);
+
+    Ok(())
+}
+
+/// Keeps the client busy without ever answering
+async fn tarpit(mut client_stream: impl AsyncRead + AsyncWrite + Unpin, duration: Duration) {
+    let mut buffer = [0u8; 1024];
+    let _ = tokio::time::timeout(duration, async {
+        while let Ok(1..) = client_stream.read(&mut buffer).await {}
+        // the client closed the connection, keep our side open anyway
+        std::future::pending::<()>().await;
+    })
+    .await;
 }
@@ -0,0 +1,181 @@
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    spawn,
+};
+use tracing::{debug, info};
+
+use crate::state::State;
+
+/// Counters of a single pool
+#[derive(Default)]
+pub struct PoolStats {
+    /// Client connections that were served by an idle pool connection
+    pub hits: AtomicU64,
+    /// Client connections that needed a new backend connection
+    pub misses: AtomicU64,
+}
+
+impl PoolStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        if hits + misses == 0 {
+            return 0.0;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = hits as f64 / (hits + misses) as f64;
+        ratio
+    }
+}
+
+/// Renders the current state in the Prometheus text exposition format
+pub fn render(state: &State) -> String {
+    let mut out = String::new();
+
+    let mut pools: Vec<_> = state.pools.iter().collect();
+    pools.sort_by_key(|(name, _pool)| *name);
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&str) -> String| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (pool_name, _pool) in &pools {
+            let _ = writeln!(out, "{name}{{pool={pool_name:?}}} {}", value(pool_name));
+        }
+    };
+
+    let pool = |name: &str| &state.pools[name];
+
+    metric(
+        "tlslb_pool_idle_connections",
+        "gauge",
+        "Idle backend connections held in the pool",
+        &|name| pool(name).idle_count().to_string(),
+    );
+    metric(
+        "tlslb_pool_idle_target",
+        "gauge",
+        "Target count of idle backend connections",
+        &|name| pool(name).idle_target().to_string(),
+    );
+    metric(
+        "tlslb_pool_hits_total",
+        "counter",
+        "Client connections served by an idle pool connection",
+        &|name| pool(name).stats.hits.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "tlslb_pool_misses_total",
+        "counter",
+        "Client connections that had to wait for a new backend connection",
+        &|name| pool(name).stats.misses.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "tlslb_pool_hit_ratio",
+        "gauge",
+        "Ratio of pool hits to all client connections",
+        &|name| pool(name).stats.hit_ratio().to_string(),
+    );
+    metric(
+        "tlslb_pool_active_clients",
+        "gauge",
+        "Client connections holding a slot (pools with max-connections only)",
+        &|name| {
+            pool(name)
+                .queue
+                .as_ref()
+                .map_or(0, |queue| queue.active())
+                .to_string()
+        },
+    );
+    metric(
+        "tlslb_pool_queued_clients",
+        "gauge",
+        "Clients waiting for a free slot (pools with max-connections only)",
+        &|name| {
+            pool(name)
+                .queue
+                .as_ref()
+                .map_or(0, |queue| queue.waiting.load(Ordering::Relaxed))
+                .to_string()
+        },
+    );
+    metric(
+        "tlslb_pool_throttled_seconds_total",
+        "counter",
+        "Time client connections waited for the bandwidth limits (pools with bandwidth only)",
+        &|name| {
+            pool(name)
+                .shaper
+                .as_ref()
+                .map_or(0.0, |shaper| shaper.throttled().as_secs_f64())
+                .to_string()
+        },
+    );
+    metric(
+        "tlslb_pool_arrival_rate",
+        "gauge",
+        "Smoothed client connection arrival rate per second (adaptive pools only)",
+        &|name| {
+            pool(name)
+                .sizer
+                .as_ref()
+                .map_or(0.0, |sizer| sizer.arrival_rate())
+                .to_string()
+        },
+    );
+    metric(
+        "tlslb_pool_connect_latency_seconds",
+        "gauge",
+        "Smoothed backend connect latency (adaptive pools only)",
+        &|name| {
+            pool(name)
+                .sizer
+                .as_ref()
+                .map_or(0.0, |sizer| sizer.connect_latency().as_secs_f64())
+                .to_string()
+        },
+    );
+
+    out
+}
+
+/// Binds the socket of the metrics endpoint
+pub async fn bind(listen_address: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(listen_address)
+        .await
+        .context("failed to bind metrics socket")?;
+    info!(%listen_address, "serving metrics");
+    Ok(listener)
+}
+
+/// Serves [`render`] over HTTP on every request
+pub async fn serve(listener: TcpListener, state: Arc<State>) -> Result<()> {
+    loop {
+        let (mut stream, peer_addr) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        spawn(async move {
+            // we answer every request the same way, so the request itself is not interesting
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+            let body = render(&state);
+            let response = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                debug!(?err, %peer_addr, "failed writing metrics");
+            }
+        });
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::config::AdaptivePreconnect;
+
+/// Estimates how many idle connections a pool should hold
+///
+/// Every client connection consumes one idle connection, which takes the connect latency to be
+/// replaced. By Little's law, `arrival rate * connect latency` connections are in flight on
+/// average, so the pool needs at least that many to not run empty.
+pub struct AdaptiveSizer {
+    config: AdaptivePreconnect,
+    arrivals: AtomicU64,
+    estimate: Mutex<Estimate>,
+    target: AtomicUsize,
+}
+
+struct Estimate {
+    /// Connections per second
+    arrival_rate: f64,
+    /// Seconds
+    connect_latency: f64,
+    last_update: Instant,
+}
+
+impl AdaptiveSizer {
+    pub fn new(config: AdaptivePreconnect) -> Self {
+        let target = config.min;
+        Self {
+            config,
+            arrivals: AtomicU64::new(0),
+            estimate: Mutex::new(Estimate {
+                arrival_rate: 0.0,
+                connect_latency: 0.0,
+                last_update: Instant::now(),
+            }),
+            target: AtomicUsize::new(target),
+        }
+    }
+
+    pub fn adjust_interval(&self) -> Duration {
+        Duration::from_millis(self.config.adjust_interval_ms.max(1))
+    }
+
+    pub fn record_arrival(&self) {
+        self.arrivals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connect_latency(&self, latency: Duration) {
+        let mut estimate = self.estimate.lock();
+        estimate.connect_latency = if estimate.connect_latency == 0.0 {
+            latency.as_secs_f64()
+        } else {
+            ewma(
+                estimate.connect_latency,
+                latency.as_secs_f64(),
+                self.config.smoothing,
+            )
+        };
+    }
+
+    /// Folds the arrivals since the last call into the rate estimate and returns the new target
+    pub fn update(&self) -> usize {
+        let arrivals = self.arrivals.swap(0, Ordering::Relaxed);
+        let mut estimate = self.estimate.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(estimate.last_update).as_secs_f64();
+        estimate.last_update = now;
+        if elapsed > 0.0 {
+            #[allow(clippy::cast_precision_loss)]
+            let rate = arrivals as f64 / elapsed;
+            estimate.arrival_rate = ewma(estimate.arrival_rate, rate, self.config.smoothing);
+        }
+
+        let target = littles_law_target(
+            estimate.arrival_rate,
+            estimate.connect_latency,
+            self.config.headroom,
+            self.config.min,
+            self.config.max,
+        );
+        self.target.store(target, Ordering::Relaxed);
+        target
+    }
+
+    pub fn target(&self) -> usize {
+        self.target.load(Ordering::Relaxed)
+    }
+
+    /// Smoothed arrival rate in connections per second
+    pub fn arrival_rate(&self) -> f64 {
+        self.estimate.lock().arrival_rate
+    }
+
+    /// Smoothed backend connect latency
+    pub fn connect_latency(&self) -> Duration {
+        Duration::from_secs_f64(self.estimate.lock().connect_latency)
+    }
+}
+
+fn ewma(previous: f64, sample: f64, smoothing: f64) -> f64 {
+    let smoothing = smoothing.clamp(0.0, 1.0);
+    smoothing.mul_add(sample, (1.0 - smoothing) * previous)
+}
+
+/// Idle connections needed to serve `arrival_rate` clients per second
+/// if a new connection takes `connect_latency` seconds
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+pub fn littles_law_target(
+    arrival_rate: f64,
+    connect_latency: f64,
+    headroom: f64,
+    min: usize,
+    max: usize,
+) -> usize {
+    let in_flight = (arrival_rate * connect_latency * headroom).ceil();
+    if !in_flight.is_finite() || in_flight <= 0.0 {
+        return min.min(max);
+    }
+    (in_flight.min(max as f64) as usize).clamp(min.min(max), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_littles_law_target() {
+        // no traffic
+        assert_eq!(littles_law_target(0.0, 0.010, 2.0, 2, 64), 2);
+        // 100 conn/s with 10ms connect latency => 1 in flight, doubled
+        assert_eq!(littles_law_target(100.0, 0.010, 2.0, 0, 64), 2);
+        // 1000 conn/s with 50ms => 50, doubled is capped
+        assert_eq!(littles_law_target(1000.0, 0.050, 2.0, 0, 64), 64);
+        // fractional values round up
+        assert_eq!(littles_law_target(1.0, 0.001, 1.0, 0, 64), 1);
+        assert_eq!(littles_law_target(f64::NAN, 0.001, 1.0, 3, 64), 3);
+    }
+
+    #[test]
+    fn test_ewma() {
+        assert!((ewma(10.0, 20.0, 0.5) - 15.0).abs() < f64::EPSILON);
+        assert!((ewma(10.0, 20.0, 1.0) - 20.0).abs() < f64::EPSILON);
+        assert!((ewma(10.0, 20.0, 0.0) - 10.0).abs() < f64::EPSILON);
+    }
+}
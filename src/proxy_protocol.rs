@@ -0,0 +1,160 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+
+/// Signature at the start of every PROXY protocol v2 header
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// SNI or `Host` the client connected to
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// Comma separated tags of the JA4 policy, from the range reserved for custom TLVs
+pub const PP2_TYPE_TLSLB_TAGS: u8 = 0xe0;
+
+/// Encodes a PROXY protocol v2 header for a proxied TCP connection
+///
+/// `addresses` are the source and destination, if the addresses have a different family,
+/// both are encoded as IPv6. Without addresses, like for clients of Unix sockets,
+/// the header has the `LOCAL` command and no address family.
+///
+/// # Errors
+/// If the TLVs do not fit into the 16 bit length of the header,
+/// values like the authority are controlled by the client.
+pub fn encode_v2(
+    addresses: Option<(SocketAddr, SocketAddr)>,
+    tlvs: &[(u8, &[u8])],
+) -> Result<Vec<u8>> {
+    let mut payload = Vec::with_capacity(36);
+    let (command, family) = match addresses {
+        // version 2, PROXY command
+        Some((source, destination)) => (0x21, encode_addresses(source, destination, &mut payload)),
+        // version 2, LOCAL command, AF_UNSPEC
+        None => (0x20, 0x00),
+    };
+
+    for (tlv_type, value) in tlvs {
+        payload.push(*tlv_type);
+        let length = u16::try_from(value.len())
+            .with_context(|| format!("PROXY header TLV {tlv_type:#x} is too long"))?;
+        payload.extend_from_slice(&length.to_be_bytes());
+        payload.extend_from_slice(value);
+    }
+    let length = u16::try_from(payload.len()).context("PROXY header is too long")?;
+
+    let mut header = Vec::with_capacity(16 + payload.len());
+    header.extend_from_slice(&SIGNATURE);
+    header.push(command);
+    header.push(family);
+    header.extend_from_slice(&length.to_be_bytes());
+    header.extend_from_slice(&payload);
+    Ok(header)
+}
+
+/// Appends the addresses and ports, returns the address family and protocol
+fn encode_addresses(source: SocketAddr, destination: SocketAddr, out: &mut Vec<u8>) -> u8 {
+    let family = match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            out.extend_from_slice(&source.octets());
+            out.extend_from_slice(&destination.octets());
+            // TCP over IPv4
+            0x11
+        }
+        (source, destination) => {
+            out.extend_from_slice(&to_ipv6_octets(source));
+            out.extend_from_slice(&to_ipv6_octets(destination));
+            // TCP over IPv6
+            0x21
+        }
+    };
+    out.extend_from_slice(&source.port().to_be_bytes());
+    out.extend_from_slice(&destination.port().to_be_bytes());
+    family
+}
+
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_v2_ipv4() {
+        let header = encode_v2(
+            Some((
+                "192.0.2.1:51234".parse().unwrap(),
+                "198.51.100.1:443".parse().unwrap(),
+            )),
+            &[(PP2_TYPE_AUTHORITY, b"example.com")],
+        )
+        .unwrap();
+        assert_eq!(
+            header,
+            [
+                &SIGNATURE[..],
+                &[0x21, 0x11, 0x00, 26],
+                &[192, 0, 2, 1, 198, 51, 100, 1, 0xc8, 0x22, 0x01, 0xbb],
+                &[0x02, 0x00, 11],
+                b"example.com",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_encode_v2_mixed_families() {
+        let header = encode_v2(
+            Some((
+                "[::ffff:192.0.2.1]:51234".parse().unwrap(),
+                "[2001:db8::1]:443".parse().unwrap(),
+            )),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(header[13], 0x21);
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(
+            &header[16..32],
+            &"::ffff:192.0.2.1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+    }
+
+    #[test]
+    fn test_encode_v2_local() {
+        let header = encode_v2(None, &[(PP2_TYPE_AUTHORITY, b"example.com")]).unwrap();
+        assert_eq!(
+            header,
+            [
+                &SIGNATURE[..],
+                &[0x20, 0x00, 0x00, 14],
+                &[0x02, 0x00, 11],
+                b"example.com",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_encode_v2_too_long() {
+        // like an HTTP Host header with a large max-head-size
+        let host = vec![b'a'; 40000];
+        let tags = vec![b'b'; 30000];
+        assert!(encode_v2(None, &[(PP2_TYPE_AUTHORITY, &host)]).is_ok());
+        assert!(
+            encode_v2(
+                None,
+                &[(PP2_TYPE_AUTHORITY, &host), (PP2_TYPE_TLSLB_TAGS, &tags)]
+            )
+            .is_err()
+        );
+        assert!(encode_v2(None, &[(PP2_TYPE_AUTHORITY, &[0; 70000])]).is_err());
+    }
+}
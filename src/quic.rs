@@ -0,0 +1,652 @@
+use anyhow::{Context, Result, bail, ensure};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, quic::HeaderProtectionKey},
+    hkdf,
+};
+
+/// QUIC version 1 (RFC 9000)
+pub const VERSION_1: u32 = 0x0000_0001;
+/// QUIC version 2 (RFC 9369)
+pub const VERSION_2: u32 = 0x6b33_43cf;
+
+/// Salt of the initial secrets of version 1 (RFC 9001 section 5.2)
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Salt of the initial secrets of version 2 (RFC 9369 section 3.3.1)
+const INITIAL_SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+    0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+/// Largest CRYPTO stream that is buffered to find the client hello
+const MAX_CRYPTO_LEN: usize = 1 << 16;
+
+/// Length of the sample taken from the ciphertext for header protection
+const SAMPLE_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+}
+
+/// Long header of a QUIC packet, before header protection is removed
+#[derive(Debug, PartialEq, Eq)]
+pub struct LongHeader<'a> {
+    pub version: u32,
+    pub packet_type: PacketType,
+    pub dcid: &'a [u8],
+    pub scid: &'a [u8],
+    /// Offset of the packet number within the packet
+    pn_offset: usize,
+    /// Length of the whole packet, the datagram can contain further coalesced packets
+    pub len: usize,
+}
+
+/// Checks if a datagram starts with a long header packet
+pub fn is_long_header(datagram: &[u8]) -> bool {
+    datagram.first().is_some_and(|first| first & 0x80 != 0)
+}
+
+/// Parses the long header of the first packet of a datagram
+pub fn parse_long_header(datagram: &[u8]) -> Result<LongHeader<'_>> {
+    let mut input = datagram;
+    let first = take_u8(&mut input)?;
+    ensure!(first & 0x80 != 0, "not a long header packet");
+    let version = u32::from_be_bytes(take(&mut input, 4)?.try_into()?);
+    let type_bits = (first >> 4) & 0x03;
+    let packet_type = match (version, type_bits) {
+        (VERSION_1, 0) | (VERSION_2, 1) => PacketType::Initial,
+        (VERSION_1, 1) | (VERSION_2, 2) => PacketType::ZeroRtt,
+        (VERSION_1, 2) | (VERSION_2, 3) => PacketType::Handshake,
+        (VERSION_1, 3) | (VERSION_2, 0) => PacketType::Retry,
+        _ => bail!("unsupported QUIC version {version:#010x}"),
+    };
+    let dcid_len = take_u8(&mut input)?;
+    let dcid = take(&mut input, dcid_len.into())?;
+    let scid_len = take_u8(&mut input)?;
+    let scid = take(&mut input, scid_len.into())?;
+    ensure!(
+        dcid.len() <= 20 && scid.len() <= 20,
+        "connection ID too long"
+    );
+
+    if packet_type == PacketType::Retry {
+        return Ok(LongHeader {
+            version,
+            packet_type,
+            dcid,
+            scid,
+            pn_offset: datagram.len(),
+            len: datagram.len(),
+        });
+    }
+    if packet_type == PacketType::Initial {
+        let token_len = take_varint(&mut input)?;
+        take(&mut input, usize::try_from(token_len)?)?;
+    }
+    let length = usize::try_from(take_varint(&mut input)?)?;
+    let pn_offset = datagram.len() - input.len();
+    ensure!(
+        length <= input.len(),
+        "QUIC packet is longer than the datagram"
+    );
+    Ok(LongHeader {
+        version,
+        packet_type,
+        dcid,
+        scid,
+        pn_offset,
+        len: pn_offset + length,
+    })
+}
+
+/// Keys protecting the Initial packets of a client (RFC 9001 section 5.2)
+#[derive(Debug, PartialEq, Eq)]
+pub struct InitialKeys {
+    key: [u8; 16],
+    iv: [u8; 12],
+    hp: [u8; 16],
+}
+
+impl InitialKeys {
+    /// Derives the keys from the destination connection ID of the first Initial packet
+    pub fn client(version: u32, dcid: &[u8]) -> Result<Self> {
+        let (salt, labels): (&[u8], [&[u8]; 3]) = match version {
+            VERSION_1 => (&INITIAL_SALT_V1, [b"quic key", b"quic iv", b"quic hp"]),
+            VERSION_2 => (
+                &INITIAL_SALT_V2,
+                [b"quicv2 key", b"quicv2 iv", b"quicv2 hp"],
+            ),
+            _ => bail!("unsupported QUIC version {version:#010x}"),
+        };
+        let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(dcid);
+        let mut client_secret = [0u8; 32];
+        expand_label(&initial_secret, b"client in", &mut client_secret)?;
+        let client_secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &client_secret);
+
+        let mut keys = Self {
+            key: [0; 16],
+            iv: [0; 12],
+            hp: [0; 16],
+        };
+        expand_label(&client_secret, labels[0], &mut keys.key)?;
+        expand_label(&client_secret, labels[1], &mut keys.iv)?;
+        expand_label(&client_secret, labels[2], &mut keys.hp)?;
+        Ok(keys)
+    }
+
+    fn header_protection_mask(&self, sample: &[u8]) -> Result<[u8; 5]> {
+        let hp = HeaderProtectionKey::new(&aead::quic::AES_128, &self.hp)
+            .ok()
+            .context("invalid header protection key")?;
+        hp.new_mask(sample)
+            .ok()
+            .context("invalid header protection sample")
+    }
+
+    fn nonce(&self, packet_number: u64) -> Nonce {
+        let mut nonce = self.iv;
+        for (byte, pn_byte) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+            *byte ^= pn_byte;
+        }
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn aead_key(&self) -> Result<LessSafeKey> {
+        Ok(LessSafeKey::new(
+            UnboundKey::new(&aead::AES_128_GCM, &self.key)
+                .ok()
+                .context("invalid packet key")?,
+        ))
+    }
+
+    /// Removes header and packet protection of an Initial packet and returns its frames
+    pub fn decrypt(&self, packet: &[u8], header: &LongHeader<'_>) -> Result<Vec<u8>> {
+        ensure!(
+            header.packet_type == PacketType::Initial,
+            "not an Initial packet"
+        );
+        let packet = packet.get(..header.len).context("truncated packet")?;
+        let sample = packet
+            .get(header.pn_offset + 4..header.pn_offset + 4 + SAMPLE_LEN)
+            .context("packet too short for header protection")?;
+        let mask = self.header_protection_mask(sample)?;
+
+        let mut header_bytes = packet[..header.pn_offset].to_vec();
+        header_bytes[0] ^= mask[0] & 0x0f;
+        let pn_len = usize::from(header_bytes[0] & 0x03) + 1;
+        let mut packet_number = 0u64;
+        for (pn_byte, mask_byte) in packet[header.pn_offset..header.pn_offset + pn_len]
+            .iter()
+            .zip(&mask[1..])
+        {
+            let pn_byte = pn_byte ^ mask_byte;
+            header_bytes.push(pn_byte);
+            packet_number = (packet_number << 8) | u64::from(pn_byte);
+        }
+
+        let mut payload = packet[header.pn_offset + pn_len..].to_vec();
+        let plaintext = self
+            .aead_key()?
+            .open_in_place(
+                self.nonce(packet_number),
+                Aad::from(&header_bytes),
+                &mut payload,
+            )
+            .ok()
+            .context("failed decrypting Initial packet")?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3 (RFC 8446 section 7.1) with an empty context
+fn expand_label(secret: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Result<()> {
+    struct Len(usize);
+    impl hkdf::KeyType for Len {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+
+    let out_len = u16::try_from(out.len())?.to_be_bytes();
+    let label_len = [u8::try_from(b"tls13 ".len() + label.len())?];
+    let info: [&[u8]; 5] = [&out_len, &label_len, b"tls13 ", label, &[0]];
+    secret
+        .expand(&info, Len(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .ok()
+        .context("HKDF expand failed")
+}
+
+/// Returns the data of all CRYPTO frames with their offsets
+///
+/// Fails on frames that are not allowed in Initial packets.
+pub fn crypto_frames(mut payload: &[u8]) -> Result<Vec<(u64, &[u8])>> {
+    let mut frames = Vec::new();
+    while !payload.is_empty() {
+        match take_varint(&mut payload)? {
+            // PADDING, PING
+            0x00 | 0x01 => {}
+            // ACK, ACK with ECN counts
+            frame_type @ (0x02 | 0x03) => {
+                take_varint(&mut payload)?;
+                take_varint(&mut payload)?;
+                let ranges = take_varint(&mut payload)?;
+                take_varint(&mut payload)?;
+                for _ in 0..ranges {
+                    take_varint(&mut payload)?;
+                    take_varint(&mut payload)?;
+                }
+                if frame_type == 0x03 {
+                    for _ in 0..3 {
+                        take_varint(&mut payload)?;
+                    }
+                }
+            }
+            // CRYPTO
+            0x06 => {
+                let offset = take_varint(&mut payload)?;
+                let len = usize::try_from(take_varint(&mut payload)?)?;
+                frames.push((offset, take(&mut payload, len)?));
+            }
+            // CONNECTION_CLOSE
+            0x1c => {
+                take_varint(&mut payload)?;
+                take_varint(&mut payload)?;
+                let reason_len = usize::try_from(take_varint(&mut payload)?)?;
+                take(&mut payload, reason_len)?;
+            }
+            frame_type => bail!("frame type {frame_type:#x} is not allowed in Initial packets"),
+        }
+    }
+    Ok(frames)
+}
+
+/// Reassembles the CRYPTO stream of the Initial packets of a connection
+///
+/// Clients may split the client hello into frames in any order and over multiple packets.
+#[derive(Debug, Default)]
+pub struct CryptoStream {
+    /// Data from offset 0 without gaps
+    contiguous: Vec<u8>,
+    /// Frames after a gap
+    pending: Vec<(u64, Vec<u8>)>,
+    buffered: usize,
+}
+
+impl CryptoStream {
+    pub fn insert(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.buffered += data.len();
+        ensure!(self.buffered <= MAX_CRYPTO_LEN, "CRYPTO stream is too long");
+        self.pending.push((offset, data.to_vec()));
+
+        // merge everything that connects to the contiguous data
+        loop {
+            let end = self.contiguous.len() as u64;
+            let Some(index) = self
+                .pending
+                .iter()
+                .position(|(offset, _data)| *offset <= end)
+            else {
+                return Ok(());
+            };
+            let (offset, data) = self.pending.swap_remove(index);
+            let skip = usize::try_from(end - offset)?;
+            if let Some(new) = data.get(skip..) {
+                self.contiguous.extend_from_slice(new);
+            }
+        }
+    }
+
+    /// The client hello as TLS record, once it is complete
+    pub fn client_hello_record(&self) -> Option<Vec<u8>> {
+        let [msg_type, a, b, c, ..] = self.contiguous[..] else {
+            return None;
+        };
+        // the handshake type of a client hello
+        if msg_type != 0x01 {
+            return None;
+        }
+        let len = 4 + usize::from_be_bytes([0, 0, 0, 0, 0, a, b, c]);
+        let message = self.contiguous.get(..len)?;
+        let record_len = u16::try_from(len).ok()?;
+        Some([&[0x16, 0x03, 0x01], &record_len.to_be_bytes()[..], message].concat())
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    ensure!(input.len() >= len, "truncated QUIC packet");
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_u8(input: &mut &[u8]) -> Result<u8> {
+    Ok(take(input, 1)?[0])
+}
+
+/// Variable-length integer (RFC 9000 section 16)
+fn take_varint(input: &mut &[u8]) -> Result<u64> {
+    let first = *input.first().context("truncated QUIC packet")?;
+    let len = 1 << (first >> 6);
+    let bytes = take(input, len)?;
+    Ok(bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| {
+            (value << 8) | u64::from(*byte)
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tls_client_hello_parser::ClientHello;
+
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// CRYPTO frame in the client Initial packets of RFC 9001 and RFC 9369 appendix A.2
+    const CLIENT_CRYPTO_FRAME: &str = "\
+        060040f1010000ed0303ebf8fa56f12939b9584a3896472ec40bb863cfd3e868\
+        04fe3a47f06a2b69484c00000413011302010000c000000010000e00000b6578\
+        616d706c652e636f6dff01000100000a00080006001d00170018001000070005\
+        04616c706e000500050100000000003300260024001d00209370b2c9caa47fba\
+        baf4fe7bf3a7a3ad9ff8236b8b54d8ebd72b9e4fe6c8d30f002b000302030400\
+        0d0010000e0403050306030203080408050806002d00020101001c0002400100\
+        3900320408ffffffffffffffff05048000ffff07048000ffff08011001048000\
+        75300901100f088394c8f03e51570806048000ffff";
+
+    /// Client Initial packet of RFC 9001 appendix A.2
+    const CLIENT_INITIAL_V1: &str = "\
+        c000000001088394c8f03e5157080000449e7b9aec34d1b1c98dd7689fb8ec11\
+        d242b123dc9bd8bab936b47d92ec356c0bab7df5976d27cd449f63300099f399\
+        1c260ec4c60d17b31f8429157bb35a1282a643a8d2262cad67500cadb8e7378c\
+        8eb7539ec4d4905fed1bee1fc8aafba17c750e2c7ace01e6005f80fcb7df6212\
+        30c83711b39343fa028cea7f7fb5ff89eac2308249a02252beba3d5a60ad264c\
+        9c6083df14577595c5d898384eaf12154682e9cf012f9021a6f0be17ddd0c208\
+        4dce25ff9b06cde535d0f920a2db1bf362c23e596d11a4f5a6cf3948838a3aec\
+        4e15daf8500a6ef69ec4e3feb6b1d98e610ac8b7ec3faf6ad760b7bad1db4ba3\
+        485e8a94dc250ae3fdb41ed15fb6a8e5eba0fc3dd60bc8e30c5c4287e53805db\
+        059ae0648db2f64264ed5e39be2e20d82df566da8dd5998ccabdae053060ae6c\
+        7b4378e846d29f37ed7b4ea9ec5d82e7961b7f25a9323851f681d582363aa5f8\
+        9937f5a67258bf63ad6f1a0b1d96dbd4faddfcefc5266ba6611722395c906556\
+        be52afe3f565636ad1b17d508b73d8743eeb524be22b3dcbc2c7468d54119c74\
+        68449a13d8e3b95811a198f3491de3e7fe942b330407abf82a4ed7c1b311663a\
+        c69890f4157015853d91e923037c227a33cdd5ec281ca3f79c44546b9d90ca00\
+        f064c99e3dd97911d39fe9c5d0b23a229a234cb36186c4819e8b9c5927726632\
+        291d6a418211cc2962e20fe47feb3edf330f2c603a9d48c0fcb5699dbfe58964\
+        25c5bac4aee82e57a85aaf4e2513e4f05796b07ba2ee47d80506f8d2c25e50fd\
+        14de71e6c418559302f939b0e1abd576f279c4b2e0feb85c1f28ff18f58891ff\
+        ef132eef2fa09346aee33c28eb130ff28f5b766953334113211996d20011a198\
+        e3fc433f9f2541010ae17c1bf202580f6047472fb36857fe843b19f5984009dd\
+        c324044e847a4f4a0ab34f719595de37252d6235365e9b84392b061085349d73\
+        203a4a13e96f5432ec0fd4a1ee65accdd5e3904df54c1da510b0ff20dcc0c77f\
+        cb2c0e0eb605cb0504db87632cf3d8b4dae6e705769d1de354270123cb11450e\
+        fc60ac47683d7b8d0f811365565fd98c4c8eb936bcab8d069fc33bd801b03ade\
+        a2e1fbc5aa463d08ca19896d2bf59a071b851e6c239052172f296bfb5e724047\
+        90a2181014f3b94a4e97d117b438130368cc39dbb2d198065ae3986547926cd2\
+        162f40a29f0c3c8745c0f50fba3852e566d44575c29d39a03f0cda721984b6f4\
+        40591f355e12d439ff150aab7613499dbd49adabc8676eef023b15b65bfc5ca0\
+        6948109f23f350db82123535eb8a7433bdabcb909271a6ecbcb58b936a88cd4e\
+        8f2e6ff5800175f113253d8fa9ca8885c2f552e657dc603f252e1a8e308f76f0\
+        be79e2fb8f5d5fbbe2e30ecadd220723c8c0aea8078cdfcb3868263ff8f09400\
+        54da48781893a7e49ad5aff4af300cd804a6b6279ab3ff3afb64491c85194aab\
+        760d58a606654f9f4400e8b38591356fbf6425aca26dc85244259ff2b19c41b9\
+        f96f3ca9ec1dde434da7d2d392b905ddf3d1f9af93d1af5950bd493f5aa731b4\
+        056df31bd267b6b90a079831aaf579be0a39013137aac6d404f518cfd4684064\
+        7e78bfe706ca4cf5e9c5453e9f7cfd2b8b4c8d169a44e55c88d4a9a7f9474241\
+        99b6f01ae39978710a2633bf44c61e4e";
+
+    /// Client Initial packet of RFC 9369 appendix A.2
+    const CLIENT_INITIAL_V2: &str = "\
+        d76b3343cf088394c8f03e5157080000449ea0c95e82ffe67b6abcdb4298b485\
+        dd04de806071bf03dceebfa162e75d6c96058bdbfb127cdfcbf903388e99ad04\
+        9f9a3dd4425ae4d0992cfff18ecf0fdb5a842d09747052f17ac2053d21f57c5d\
+        250f2c4f0e0202b70785b7946e992e58a59ac52dea6774d4f03b55545243cf1a\
+        12834e3f249a78d395e0d18f4d766004f1a2674802a747ea02e5ddecda4a2b89\
+        128b5ca16dfb550eb4490d261f07f0de1c6054196a11cbea40afb6ef5253cd68\
+        18f6625efce3b6def6ba7e4b37a40f7732e093daa7d52190935b8da58976ff33\
+        12ae50b187c1433c0f028edcc4c2838b6a9bfc226ca4b4530e7a4ccee1bfa2a3\
+        d396ae5a3fb512384b2fdd851f784a65e03f2c4fbe11a53c7777c023462239dd\
+        6f7521a3f6c7d5dd3ec9b3f233773d4b46d23cc375eb198c63301c21801f6520\
+        bcfb7966fc49b393f0061d974a2706df8c4a9449f11d7f3d2dcbb90c6b877045\
+        636e7c0c0fe4eb0f697545460c806910d2c355f1d253bc9d2452aaa549e27a1f\
+        ac7cf4ed77f322e8fa894b6a83810a34b361901751a6f5eb65a0326e07de7c12\
+        16ccce2d0193f958bb3850a833f7ae432b65bc5a53975c155aa4bcb4f7b2c4e5\
+        4df16efaf6ddea94e2c50b4cd1dfe06017e0e9d02900cffe1935e0491d77ffb4\
+        fdf85290fdd893d577b1131a610ef6a5c32b2ee0293617a37cbb08b847741c3b\
+        8017c25ca9052ca1079d8b78aebd47876d330a30f6a8c6d61dd1ab5589329de7\
+        14d19d61370f8149748c72f132f0fc99f34d766c6938597040d8f9e2bb522ff9\
+        9c63a344d6a2ae8aa8e51b7b90a4a806105fcbca31506c446151adfeceb51b91\
+        abfe43960977c87471cf9ad4074d30e10d6a7f03c63bd5d4317f68ff325ba3bd\
+        80bf4dc8b52a0ba031758022eb025cdd770b44d6d6cf0670f4e990b22347a7db\
+        848265e3e5eb72dfe8299ad7481a408322cac55786e52f633b2fb6b614eaed18\
+        d703dd84045a274ae8bfa73379661388d6991fe39b0d93debb41700b41f90a15\
+        c4d526250235ddcd6776fc77bc97e7a417ebcb31600d01e57f32162a8560cacc\
+        7e27a096d37a1a86952ec71bd89a3e9a30a2a26162984d7740f81193e8238e61\
+        f6b5b984d4d3dfa033c1bb7e4f0037febf406d91c0dccf32acf423cfa1e70710\
+        10d3f270121b493ce85054ef58bada42310138fe081adb04e2bd901f2f13458b\
+        3d6758158197107c14ebb193230cd1157380aa79cae1374a7c1e5bbcb80ee23e\
+        06ebfde206bfb0fcbc0edc4ebec309661bdd908d532eb0c6adc38b7ca7331dce\
+        8dfce39ab71e7c32d318d136b6100671a1ae6a6600e3899f31f0eed19e3417d1\
+        34b90c9058f8632c798d4490da4987307cba922d61c39805d072b589bd52fdf1\
+        e86215c2d54e6670e07383a27bbffb5addf47d66aa85a0c6f9f32e59d85a44dd\
+        5d3b22dc2be80919b490437ae4f36a0ae55edf1d0b5cb4e9a3ecabee93dfc6e3\
+        8d209d0fa6536d27a5d6fbb17641cde27525d61093f1b28072d111b2b4ae5f89\
+        d5974ee12e5cf7d5da4d6a31123041f33e61407e76cffcdcfd7e19ba58cf4b53\
+        6f4c4938ae79324dc402894b44faf8afbab35282ab659d13c93f70412e85cb19\
+        9a37ddec600545473cfb5a05e08d0b209973b2172b4d21fb69745a262ccde96b\
+        21304c9fa6a19981edb809e9041a7ba4";
+
+    fn encode_varint(value: u64) -> Vec<u8> {
+        match value {
+            0..0x40 => vec![u8::try_from(value).unwrap()],
+            0x40..0x4000 => (u16::try_from(value).unwrap() | 0x4000)
+                .to_be_bytes()
+                .to_vec(),
+            _ => (u32::try_from(value).unwrap() | 0x8000_0000)
+                .to_be_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// Protects an Initial packet like a client
+    fn seal_initial(version: u32, dcid: &[u8], packet_number: u8, frames: &[u8]) -> Vec<u8> {
+        let keys = InitialKeys::client(version, dcid).unwrap();
+        let type_bits = if version == VERSION_2 { 0x10 } else { 0x00 };
+        // one byte packet number
+        let mut packet = vec![0xc0 | type_bits];
+        packet.extend_from_slice(&version.to_be_bytes());
+        packet.push(u8::try_from(dcid.len()).unwrap());
+        packet.extend_from_slice(dcid);
+        packet.push(0);
+        packet.push(0);
+        packet.extend(encode_varint(1 + frames.len() as u64 + 16));
+        let pn_offset = packet.len();
+        packet.push(packet_number);
+
+        let mut payload = frames.to_vec();
+        keys.aead_key()
+            .unwrap()
+            .seal_in_place_append_tag(
+                keys.nonce(packet_number.into()),
+                Aad::from(&packet),
+                &mut payload,
+            )
+            .unwrap();
+        packet.extend(payload);
+
+        let mask = keys
+            .header_protection_mask(&packet[pn_offset + 4..pn_offset + 4 + SAMPLE_LEN])
+            .unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+        packet
+    }
+
+    fn crypto_frame(offset: u64, data: &[u8]) -> Vec<u8> {
+        [
+            vec![0x06],
+            encode_varint(offset),
+            encode_varint(data.len() as u64),
+            data.to_vec(),
+        ]
+        .concat()
+    }
+
+    /// Minimal TLS 1.3 client hello with SNI and ALPN
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let name_len = u16::try_from(name.len()).unwrap();
+        let server_name = [
+            &(name_len + 3).to_be_bytes()[..],
+            &[0],
+            &name_len.to_be_bytes(),
+            name,
+        ]
+        .concat();
+        let extensions = [
+            &[0x00, 0x00][..],
+            &u16::try_from(server_name.len()).unwrap().to_be_bytes(),
+            &server_name,
+            &[0x00, 0x10, 0x00, 0x05, 0x00, 0x03, 0x02, b'h', b'3'],
+            &[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04],
+        ]
+        .concat();
+        let body = [
+            &[0x03, 0x03][..],
+            &[0x42; 32],
+            &[0x00],
+            &[0x00, 0x02, 0x13, 0x01],
+            &[0x01, 0x00],
+            &u16::try_from(extensions.len()).unwrap().to_be_bytes(),
+            &extensions,
+        ]
+        .concat();
+        let len = u32::try_from(body.len()).unwrap().to_be_bytes();
+        [&[0x01], &len[1..], &body[..]].concat()
+    }
+
+    #[test]
+    fn test_initial_keys_v1() {
+        // RFC 9001 appendix A.1
+        let keys = InitialKeys::client(VERSION_1, &hex("8394c8f03e515708")).unwrap();
+        assert_eq!(keys.key.to_vec(), hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(keys.hp.to_vec(), hex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+
+    #[test]
+    fn test_initial_keys_v2() {
+        // RFC 9369 appendix A.1
+        let keys = InitialKeys::client(VERSION_2, &hex("8394c8f03e515708")).unwrap();
+        assert_eq!(keys.key.to_vec(), hex("8b1a0bc121284290a29e0971b5cd045d"));
+        assert_eq!(keys.iv.to_vec(), hex("91f73e2351d8fa91660e909f"));
+        assert_eq!(keys.hp.to_vec(), hex("45b95e15235d6f45a6b19cbcb0294ba9"));
+    }
+
+    /// Decrypts a client Initial packet of the RFCs and checks its client hello
+    fn check_rfc_client_initial(packet: &str, version: u32) {
+        let packet = hex(packet);
+        let header = parse_long_header(&packet).unwrap();
+        assert_eq!(header.version, version);
+        assert_eq!(header.packet_type, PacketType::Initial);
+        assert_eq!(header.dcid, hex("8394c8f03e515708"));
+        assert_eq!(header.len, 1200);
+
+        let keys = InitialKeys::client(header.version, header.dcid).unwrap();
+        let payload = keys.decrypt(&packet, &header).unwrap();
+        let crypto_frame = hex(CLIENT_CRYPTO_FRAME);
+        assert_eq!(payload.len(), 1162);
+        assert_eq!(payload[..crypto_frame.len()], crypto_frame);
+        assert!(payload[crypto_frame.len()..].iter().all(|&byte| byte == 0));
+
+        let frames = crypto_frames(&payload).unwrap();
+        assert_eq!(frames, [(0, &crypto_frame[4..])]);
+        let mut stream = CryptoStream::default();
+        stream.insert(frames[0].0, frames[0].1).unwrap();
+        let record = stream.client_hello_record().unwrap();
+        let client_hello = ClientHello::try_from(record.as_slice()).unwrap();
+        assert_eq!(client_hello.sni(), Some("example.com"));
+        assert_eq!(client_hello.alpn(), [b"alpn"]);
+    }
+
+    #[test]
+    fn test_rfc_client_initial_v1() {
+        check_rfc_client_initial(CLIENT_INITIAL_V1, VERSION_1);
+    }
+
+    #[test]
+    fn test_rfc_client_initial_v2() {
+        check_rfc_client_initial(CLIENT_INITIAL_V2, VERSION_2);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dcid = hex("8394c8f03e515708");
+        let hello = client_hello("example.com");
+        for version in [VERSION_1, VERSION_2] {
+            // second half first, as some clients do
+            let (first, second) = hello.split_at(20);
+            let frames = [
+                crypto_frame(20, second),
+                vec![0x01],
+                crypto_frame(0, first),
+                vec![0x00; 40],
+            ]
+            .concat();
+            let packet = seal_initial(version, &dcid, 0, &frames);
+
+            let header = parse_long_header(&packet).unwrap();
+            assert_eq!(header.packet_type, PacketType::Initial);
+            assert_eq!(header.dcid, dcid);
+            assert_eq!(header.len, packet.len());
+
+            let keys = InitialKeys::client(header.version, header.dcid).unwrap();
+            let payload = keys.decrypt(&packet, &header).unwrap();
+            assert_eq!(payload, frames);
+
+            let mut stream = CryptoStream::default();
+            for (offset, data) in crypto_frames(&payload).unwrap() {
+                stream.insert(offset, data).unwrap();
+            }
+            let record = stream.client_hello_record().unwrap();
+            let client_hello = ClientHello::try_from(record.as_slice()).unwrap();
+            assert_eq!(client_hello.sni(), Some("example.com"));
+            assert_eq!(client_hello.alpn(), [b"h3"]);
+        }
+    }
+
+    #[test]
+    fn test_incomplete_client_hello() {
+        let hello = client_hello("example.com");
+        let mut stream = CryptoStream::default();
+        stream.insert(30, &hello[30..]).unwrap();
+        assert_eq!(stream.client_hello_record(), None);
+        // overlapping retransmission
+        stream.insert(0, &hello[..35]).unwrap();
+        assert!(stream.client_hello_record().is_some());
+    }
+
+    #[test]
+    fn test_tampered_packet() {
+        let dcid = hex("8394c8f03e515708");
+        let mut packet = seal_initial(VERSION_1, &dcid, 1, &[0x01; 32]);
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        let header = parse_long_header(&packet).unwrap();
+        let keys = InitialKeys::client(VERSION_1, &dcid).unwrap();
+        assert!(keys.decrypt(&packet, &header).is_err());
+    }
+}
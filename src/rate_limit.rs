@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::{
+    access::close_denied,
+    config::{RateLimit, RateLimitKey, TlsAlert},
+    stream::Stream,
+};
+
+/// Count of independently locked parts of the store of a limiter
+const SHARDS: usize = 16;
+
+/// Interval in which keys without connections and with a full bucket are removed
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// All rate limits of a frontend or backend
+pub struct RateLimits {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    /// Creates the limiters and spawns the tasks expiring stale keys
+    pub fn new(configs: &[RateLimit]) -> Result<Self> {
+        let mut limiters = Vec::with_capacity(configs.len());
+        for config in configs {
+            let limiter = Arc::new(RateLimiter::new(config.clone())?);
+            tokio::spawn(RateLimiter::expire_periodically(Arc::downgrade(&limiter)));
+            limiters.push(limiter);
+        }
+        Ok(Self { limiters })
+    }
+
+    /// Applies all limits whose key is known
+    ///
+    /// Limits keyed by JA4 are skipped if `ja4` is `None`,
+    /// limits keyed by IP address or prefix if `ip` is `None` like for clients of Unix sockets.
+    /// The returned permits hold the concurrency slots until they are dropped.
+    pub fn acquire(
+        &self,
+        ip: Option<IpAddr>,
+        asn: Option<u32>,
+        ja4: Option<&str>,
+    ) -> Result<Vec<LimitPermit>, &RateLimiter> {
+        self.acquire_matching(|_| true, ip, asn, ja4)
+    }
+
+    /// Applies only the limits keyed by JA4, for after [`Self::acquire`] was called without one
+    pub fn acquire_ja4(&self, ja4: &str) -> Result<Vec<LimitPermit>, &RateLimiter> {
+        self.acquire_matching(|key| key == RateLimitKey::Ja4, None, None, Some(ja4))
+    }
+
+    fn acquire_matching(
+        &self,
+        filter: impl Fn(RateLimitKey) -> bool,
+        ip: Option<IpAddr>,
+        asn: Option<u32>,
+        ja4: Option<&str>,
+    ) -> Result<Vec<LimitPermit>, &RateLimiter> {
+        let mut permits = Vec::new();
+        for limiter in &self.limiters {
+            if !filter(limiter.config.key) {
+                continue;
+            }
+            let Some(key) = limiter.client_key(ip, asn, ja4) else {
+                continue;
+            };
+            permits.push(
+                limiter
+                    .acquire(key, Instant::now())
+                    .ok_or(limiter.as_ref())?,
+            );
+        }
+        Ok(permits)
+    }
+}
+
+/// A single limit with the state of every client
+pub struct RateLimiter {
+    config: RateLimit,
+    hasher: RandomState,
+    shards: [Mutex<HashMap<ClientKey, Bucket>>; SHARDS],
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum ClientKey {
+    Ip(IpAddr),
+    Prefix(IpNet),
+    Asn(u32),
+    Ja4(Box<str>),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    concurrent: u32,
+}
+
+impl RateLimiter {
+    fn new(config: RateLimit) -> Result<Self> {
+        if config.ipv4_prefix_len > 32 || config.ipv6_prefix_len > 128 {
+            bail!("invalid prefix length in rate limit");
+        }
+        // NaN would never refill or never limit
+        if config
+            .rate
+            .is_some_and(|rate| !(rate > 0.0 && rate.is_finite()))
+        {
+            bail!("rate limit must be positive and finite");
+        }
+        if config
+            .burst
+            .is_some_and(|burst| !(burst > 0.0 && burst.is_finite()))
+        {
+            bail!("burst of rate limit must be positive and finite");
+        }
+        Ok(Self {
+            config,
+            hasher: RandomState::new(),
+            shards: Default::default(),
+        })
+    }
+
+    pub const fn key(&self) -> RateLimitKey {
+        self.config.key
+    }
+
+    /// Closes the connection of a limited client as configured
+    pub async fn deny(&self, stream: Stream) -> Result<()> {
+        close_denied(
+            stream,
+            self.config.deny_action,
+            self.config.alert.unwrap_or(TlsAlert::HandshakeFailure),
+        )
+        .await
+    }
+
+    fn burst(&self) -> f64 {
+        self.config
+            .burst
+            .or(self.config.rate)
+            .unwrap_or_default()
+            .max(1.0)
+    }
+
+    fn client_key(
+        &self,
+        ip: Option<IpAddr>,
+        asn: Option<u32>,
+        ja4: Option<&str>,
+    ) -> Option<ClientKey> {
+        let ip = ip.map(|ip| ip.to_canonical());
+        match self.config.key {
+            RateLimitKey::Ip => ip.map(ClientKey::Ip),
+            RateLimitKey::Prefix => {
+                let ip = ip?;
+                let prefix_len = match ip {
+                    IpAddr::V4(_) => self.config.ipv4_prefix_len,
+                    IpAddr::V6(_) => self.config.ipv6_prefix_len,
+                };
+                IpNet::new(ip, prefix_len)
+                    .ok()
+                    .map(|net| ClientKey::Prefix(net.trunc()))
+            }
+            RateLimitKey::Asn => asn.map(ClientKey::Asn),
+            RateLimitKey::Ja4 => ja4.map(|ja4| ClientKey::Ja4(ja4.into())),
+        }
+    }
+
+    fn shard(&self, key: &ClientKey) -> &Mutex<HashMap<ClientKey, Bucket>> {
+        #[allow(clippy::cast_possible_truncation)]
+        let index = self.hasher.hash_one(key) as usize % SHARDS;
+        &self.shards[index]
+    }
+
+    /// Takes a token from the bucket of the client and a concurrency slot
+    fn acquire(self: &Arc<Self>, key: ClientKey, now: Instant) -> Option<LimitPermit> {
+        let burst = self.burst();
+        let mut shard = self.shard(&key).lock();
+        let bucket = shard.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            concurrent: 0,
+        });
+        bucket.refill(now, self.config.rate, burst);
+
+        if self
+            .config
+            .max_concurrent
+            .is_some_and(|max| bucket.concurrent >= max)
+        {
+            return None;
+        }
+        if self.config.rate.is_some() {
+            if bucket.tokens < 1.0 {
+                return None;
+            }
+            bucket.tokens -= 1.0;
+        }
+        bucket.concurrent += 1;
+
+        Some(LimitPermit {
+            limiter: Arc::clone(self),
+            key,
+        })
+    }
+
+    /// Removes clients without connections whose bucket is full again
+    fn expire(&self, now: Instant) -> usize {
+        let burst = self.burst();
+        let mut expired = 0;
+        for shard in &self.shards {
+            shard.lock().retain(|_key, bucket| {
+                bucket.refill(now, self.config.rate, burst);
+                let keep = bucket.concurrent > 0 || bucket.tokens < burst;
+                expired += usize::from(!keep);
+                keep
+            });
+        }
+        expired
+    }
+
+    async fn expire_periodically(limiter: Weak<Self>) {
+        let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(limiter) = limiter.upgrade() else {
+                return;
+            };
+            let expired = limiter.expire(Instant::now());
+            if expired > 0 {
+                debug!(expired, key = ?limiter.config.key, "expired rate limit keys");
+            }
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: Option<f64>, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = rate.map_or(burst, |rate| (self.tokens + elapsed * rate).min(burst));
+        self.updated = now;
+    }
+}
+
+/// A concurrency slot of a client, released on drop
+pub struct LimitPermit {
+    limiter: Arc<RateLimiter>,
+    key: ClientKey,
+}
+
+impl Drop for LimitPermit {
+    fn drop(&mut self) {
+        if let Some(bucket) = self.limiter.shard(&self.key).lock().get_mut(&self.key) {
+            bucket.concurrent = bucket.concurrent.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn limiter(config: &str) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(toml::from_str(config).unwrap()).unwrap())
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 23));
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = limiter(
+            r#"
+            key = "ip"
+            rate = 2.0
+            burst = 3.0
+            "#,
+        );
+        let key = limiter.client_key(Some(CLIENT), None, None).unwrap();
+        let start = Instant::now();
+
+        let permits: Vec<_> = (0..3)
+            .map(|_| limiter.acquire(key.clone(), start).unwrap())
+            .collect();
+        assert!(limiter.acquire(key.clone(), start).is_none());
+        // closing connections does not give back tokens
+        drop(permits);
+        assert!(limiter.acquire(key.clone(), start).is_none());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.acquire(key.clone(), later).is_some());
+        assert!(limiter.acquire(key, later).is_none());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for config in [
+            "key = \"ip\"\nrate = 0.0",
+            "key = \"ip\"\nrate = -1.0",
+            "key = \"ip\"\nrate = nan",
+            "key = \"ip\"\nrate = inf",
+            "key = \"ip\"\nrate = 1.0\nburst = nan",
+            "key = \"prefix\"\nipv4-prefix-len = 33",
+        ] {
+            assert!(
+                RateLimiter::new(toml::from_str(config).unwrap()).is_err(),
+                "{config}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let limiter = limiter(
+            r#"
+            key = "prefix"
+            max-concurrent = 2
+            "#,
+        );
+        let key = |ip: [u8; 4]| {
+            limiter
+                .client_key(Some(IpAddr::from(ip)), None, None)
+                .unwrap()
+        };
+        let now = Instant::now();
+
+        let first = limiter.acquire(key([203, 0, 113, 1]), now).unwrap();
+        let _second = limiter.acquire(key([203, 0, 113, 2]), now).unwrap();
+        assert!(limiter.acquire(key([203, 0, 113, 3]), now).is_none());
+        assert!(limiter.acquire(key([198, 51, 100, 1]), now).is_some());
+
+        drop(first);
+        assert!(limiter.acquire(key([203, 0, 113, 3]), now).is_some());
+    }
+
+    #[test]
+    fn test_client_key() {
+        let prefix = limiter(r#"key = "prefix""#);
+        assert_eq!(
+            prefix.client_key(Some("2001:db8:1:2::1".parse().unwrap()), None, None),
+            Some(ClientKey::Prefix("2001:db8:1::/48".parse().unwrap()))
+        );
+        assert_eq!(
+            prefix.client_key(
+                Some(IpAddr::V6(Ipv4Addr::new(203, 0, 113, 23).to_ipv6_mapped())),
+                None,
+                None
+            ),
+            Some(ClientKey::Prefix("203.0.113.0/24".parse().unwrap()))
+        );
+        // clients of Unix sockets
+        assert_eq!(prefix.client_key(None, None, None), None);
+
+        let asn = limiter(r#"key = "asn""#);
+        assert_eq!(asn.client_key(Some(CLIENT), None, None), None);
+        assert_eq!(
+            asn.client_key(Some(CLIENT), Some(64496), None),
+            Some(ClientKey::Asn(64496))
+        );
+    }
+
+    #[test]
+    fn test_expire() {
+        let limiter = limiter(
+            r#"
+            key = "ip"
+            rate = 1.0
+            "#,
+        );
+        let key = limiter.client_key(Some(CLIENT), None, None).unwrap();
+        let start = Instant::now();
+
+        drop(limiter.acquire(key.clone(), start).unwrap());
+        // the bucket is not full yet
+        assert_eq!(limiter.expire(start), 0);
+
+        let permit = limiter
+            .acquire(key.clone(), start + Duration::from_secs(1))
+            .unwrap();
+        // the client still has a connection
+        assert_eq!(limiter.expire(start + Duration::from_secs(10)), 0);
+        drop(permit);
+        assert_eq!(limiter.expire(start + Duration::from_secs(10)), 1);
+        assert!(limiter.shard(&key).lock().is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{fs, path::PathBuf, time::SystemTime};
+
+use parking_lot::Mutex;
+
+/// Something backed by files that are reloaded periodically
+pub trait Reload: Send + Sync {
+    /// Reloads the files if they were modified
+    ///
+    /// In case of an error, the previous state is kept.
+    fn reload_if_changed(&self);
+}
+
+/// Detects modifications of a set of files by their modification time
+pub struct FileWatch {
+    paths: Vec<PathBuf>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl FileWatch {
+    /// Creates a watch, the current state of the files is treated as unchanged
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let modified = paths.iter().map(modification_time).collect();
+        Self {
+            paths,
+            modified: Mutex::new(modified),
+        }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Returns true if any file was modified, created or removed since the last call
+    pub fn changed(&self) -> bool {
+        let current: Vec<_> = self.paths.iter().map(modification_time).collect();
+        let mut modified = self.modified.lock();
+        if *modified == current {
+            false
+        } else {
+            *modified = current;
+            true
+        }
+    }
+}
+
+fn modification_time(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
@@ -0,0 +1,147 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use tls_client_hello_parser::{ClientHello, Ja4Fingerprint};
+use tlslb::cli::RouteTestArgs;
+
+use crate::{
+    config::{Config, RouteAction, TlsVersion},
+    ech::EchKeys,
+    routing::{RouteInput, Router},
+    state::load_ip_to_asn_database,
+};
+
+/// Prints which route a client hello would hit
+pub fn run(config: &Config, args: &RouteTestArgs) -> Result<()> {
+    let router = Router::new(config)?;
+    let ip_to_asn_database = load_ip_to_asn_database();
+    let client_asn = ip_to_asn_database
+        .lookup_ip(args.client_ip)
+        .map(|v| v.asn());
+
+    let mut client_hello_bytes = match &args.client_hello {
+        Some(path) => {
+            let raw = fs::read(path).with_context(|| format!("can't read {path:?}"))?;
+            decode_hex(&raw).unwrap_or(raw)
+        }
+        None => Vec::new(),
+    };
+    // routes see the inner client hello if the frontend can decrypt ECH
+    if let Some(frontend) = config.frontends.get(&args.frontend)
+        && !frontend.ech.is_empty()
+        && let Some(inner) = EchKeys::load(&frontend.ech)?.decrypt(&client_hello_bytes)
+    {
+        println!("decrypted ECH client hello");
+        client_hello_bytes = inner;
+    }
+
+    let (sni, alpn, tls_version, ja4): (_, Vec<Vec<u8>>, _, _) = if args.client_hello.is_some() {
+        let client_hello = ClientHello::try_from(client_hello_bytes.as_slice())
+            .context("failed parsing client hello")?;
+        (
+            client_hello.sni().map(str::to_string),
+            client_hello
+                .alpn()
+                .iter()
+                .map(|alpn| alpn.to_vec())
+                .collect(),
+            client_hello.tls_version(),
+            Ja4Fingerprint::calculate(&client_hello)
+                .as_ref()
+                .to_string(),
+        )
+    } else {
+        let tls_version: TlsVersion = toml::Value::String(args.tls_version.clone())
+            .try_into()
+            .with_context(|| format!("unknown TLS version {:?}", args.tls_version))?;
+        (
+            args.sni.clone(),
+            args.alpn
+                .iter()
+                .map(|alpn| alpn.as_bytes().to_vec())
+                .collect(),
+            tls_version.wire_version(),
+            args.ja4.clone(),
+        )
+    };
+    let alpn: Vec<&[u8]> = alpn.iter().map(Vec::as_slice).collect();
+
+    let input = RouteInput {
+        frontend: &args.frontend,
+        sni: sni.as_deref(),
+        alpn: &alpn,
+        tls_version,
+        ja4: &ja4,
+        client_ip: Some(args.client_ip),
+        client_asn,
+    };
+    println!("{input:#?}");
+
+    match router.route(&input) {
+        Some(route) => {
+            let action = match &route.route.action {
+                RouteAction::Forward { backend } => format!("forward to backend {backend:?}"),
+                RouteAction::Reject { alert } => format!("reject with alert {alert:?}"),
+                RouteAction::Terminate { backend, .. } => {
+                    format!("terminate TLS and forward to backend {backend:?}")
+                }
+                RouteAction::Tarpit { duration_secs } => format!("tarpit for {duration_secs}s"),
+            };
+            println!("matched route {}: {action}", route.name());
+        }
+        None => match input
+            .sni
+            .and_then(|sni| router.backend_by_sni(&config.backends, sni, &alpn))
+        {
+            Some(backend) => println!("no route matched, forward to backend {backend:?} by SNI"),
+            None => println!("no route matched and no backend is named like the SNI"),
+        },
+    }
+
+    Ok(())
+}
+
+/// Decodes a hex string or a hex dump with offsets like it is exported by Wireshark
+fn decode_hex(input: &[u8]) -> Option<Vec<u8>> {
+    let input = std::str::from_utf8(input).ok()?;
+    let mut bytes = Vec::new();
+    for line in input.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let hex_tokens = match tokens.as_slice() {
+            [] => continue,
+            [single] => vec![*single],
+            [offset, rest @ ..] if offset.len() > 2 => rest
+                .iter()
+                .copied()
+                .take_while(|token| {
+                    token.len() == 2 && token.bytes().all(|b| b.is_ascii_hexdigit())
+                })
+                .collect(),
+            all => all.to_vec(),
+        };
+        for token in hex_tokens {
+            if token.len() % 2 != 0 {
+                return None;
+            }
+            for i in (0..token.len()).step_by(2) {
+                bytes.push(u8::from_str_radix(token.get(i..i + 2)?, 16).ok()?);
+            }
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex(b"160301"), Some(vec![0x16, 0x03, 0x01]));
+        assert_eq!(
+            decode_hex(b"0000   16 03 01 06\n0004   14 01   ..\n"),
+            Some(vec![0x16, 0x03, 0x01, 0x06, 0x14, 0x01])
+        );
+        assert_eq!(decode_hex(&[0x16, 0x03, 0x01]), None);
+    }
+}
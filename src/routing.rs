@@ -0,0 +1,414 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{ServerConfig, crypto::ring},
+};
+
+use crate::config::{AlpnMatch, Backend, Config, Route, RouteAction};
+
+/// Everything known about a client after reading its hello
+#[derive(Debug)]
+pub struct RouteInput<'a> {
+    pub frontend: &'a str,
+    pub sni: Option<&'a str>,
+    pub alpn: &'a [&'a [u8]],
+    pub tls_version: u16,
+    pub ja4: &'a str,
+    /// `None` for clients of Unix sockets
+    pub client_ip: Option<IpAddr>,
+    pub client_asn: Option<u32>,
+}
+
+/// The ordered list of routing rules
+pub struct Router {
+    routes: Vec<CompiledRoute>,
+    /// Backends that are the target of an ALPN override of another backend
+    alpn_targets: HashSet<String>,
+}
+
+pub struct CompiledRoute {
+    /// Position in the configuration file, starting at 0
+    pub index: usize,
+    pub route: Route,
+    /// Set for [`RouteAction::Terminate`]
+    pub acceptor: Option<TlsAcceptor>,
+}
+
+impl CompiledRoute {
+    /// Name of the route for logging
+    pub fn name(&self) -> String {
+        self.route
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{}", self.index))
+    }
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut routes = Vec::with_capacity(config.routes.len());
+        for (index, route) in config.routes.iter().enumerate() {
+            let backend = match &route.action {
+                RouteAction::Forward { backend } | RouteAction::Terminate { backend, .. } => {
+                    Some(backend)
+                }
+                RouteAction::Reject { .. } | RouteAction::Tarpit { .. } => None,
+            };
+            if let Some(backend) = backend
+                && !config.backends.contains_key(backend)
+            {
+                bail!("route #{index} uses unknown backend {backend:?}");
+            }
+            for frontend in &route.frontends {
+                if !config.frontends.contains_key(frontend) {
+                    bail!("route #{index} matches on unknown frontend {frontend:?}");
+                }
+            }
+
+            let acceptor = match &route.action {
+                RouteAction::Terminate {
+                    certificate,
+                    private_key,
+                    ..
+                } => Some(
+                    load_acceptor(certificate, private_key)
+                        .with_context(|| format!("failed loading TLS key for route #{index}"))?,
+                ),
+                _ => None,
+            };
+
+            routes.push(CompiledRoute {
+                index,
+                route: route.clone(),
+                acceptor,
+            });
+        }
+        let alpn_targets = config
+            .backends
+            .iter()
+            .flat_map(|(name, backend)| {
+                backend
+                    .alpn
+                    .iter()
+                    .filter(move |route| route.backend != *name)
+                    .map(|route| route.backend.clone())
+            })
+            .collect();
+        Ok(Self {
+            routes,
+            alpn_targets,
+        })
+    }
+
+    /// Returns the first route matching the client
+    pub fn route(&self, input: &RouteInput) -> Option<&CompiledRoute> {
+        self.routes
+            .iter()
+            .find(|compiled| route_matches(&compiled.route, input))
+    }
+
+    /// Selects the backend named like the SNI, used if no route matches
+    ///
+    /// The ALPN overrides of that backend are applied. Targets of ALPN overrides
+    /// are only reachable through them, so clients can not skip them by their SNI.
+    pub fn backend_by_sni<'a>(
+        &self,
+        backends: &'a HashMap<String, Arc<Backend>>,
+        sni: &'a str,
+        alpn: &[&[u8]],
+    ) -> Option<&'a str> {
+        let (name, backend) = backends.get_key_value(sni)?;
+        if self.alpn_targets.contains(name) {
+            return None;
+        }
+        match backend
+            .alpn
+            .iter()
+            .find(|route| alpn_matches(route.match_on, &route.protocols, alpn))
+        {
+            Some(route) => Some(&route.backend),
+            None => Some(name),
+        }
+    }
+}
+
+fn load_acceptor(certificate: &Path, private_key: &Path) -> Result<TlsAcceptor> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(certificate).with_context(|| format!("can't open {certificate:?}"))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("malformed certificate in {certificate:?}"))?;
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(private_key).with_context(|| format!("can't open {private_key:?}"))?,
+    ))
+    .with_context(|| format!("malformed private key in {private_key:?}"))?
+    .with_context(|| format!("no private key in {private_key:?}"))?;
+
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn route_matches(route: &Route, input: &RouteInput) -> bool {
+    (route.frontends.is_empty() || route.frontends.iter().any(|f| f == input.frontend))
+        && (route.sni.is_empty()
+            || input
+                .sni
+                .is_some_and(|sni| route.sni.iter().any(|pattern| sni_matches(pattern, sni))))
+        && (route.alpn.is_empty() || alpn_matches(route.alpn_match, &route.alpn, input.alpn))
+        && (route.tls_versions.is_empty()
+            || route
+                .tls_versions
+                .iter()
+                .any(|version| version.wire_version() == input.tls_version))
+        && ((route.ja4.is_empty() && route.ja4_prefix.is_empty())
+            || route.ja4.iter().any(|ja4| ja4 == input.ja4)
+            || route
+                .ja4_prefix
+                .iter()
+                .any(|prefix| input.ja4.starts_with(prefix.as_str())))
+        && (route.client_cidrs.is_empty()
+            || route
+                .client_cidrs
+                .iter()
+                .any(|net| input.client_ip.is_some_and(|ip| net.contains(&ip))))
+        && (route.client_asns.is_empty()
+            || input
+                .client_asn
+                .is_some_and(|asn| route.client_asns.contains(&asn)))
+}
+
+/// Matches a SNI against a pattern
+///
+/// `*` matches every SNI, `*.example.com` matches all subdomains of `example.com`
+/// and everything else has to match exactly. The comparison is case insensitive.
+pub fn sni_matches(pattern: &str, sni: &str) -> bool {
+    let sni = sni.trim_end_matches('.');
+    if pattern == "*" {
+        true
+    } else if let Some(suffix) = pattern.strip_prefix("*.") {
+        sni.len() > suffix.len() + 1
+            && sni.as_bytes()[sni.len() - suffix.len() - 1] == b'.'
+            && sni[sni.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+    } else {
+        pattern.trim_end_matches('.').eq_ignore_ascii_case(sni)
+    }
+}
+
+/// Checks the ALPN protocols offered by a client against a list of protocols
+pub fn alpn_matches(match_on: AlpnMatch, protocols: &[String], offered: &[&[u8]]) -> bool {
+    let is_listed = |offered: &&[u8]| {
+        protocols
+            .iter()
+            .any(|protocol| protocol.as_bytes() == *offered)
+    };
+    match match_on {
+        AlpnMatch::First => offered.first().is_some_and(is_listed),
+        AlpnMatch::Any => offered.iter().any(is_listed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn input() -> RouteInput<'static> {
+        RouteInput {
+            frontend: "https",
+            sni: Some("www.example.com"),
+            alpn: &[b"h2", b"http/1.1"],
+            tls_version: 0x0304,
+            ja4: "t13d1516h2_8daaf6152771_02713d6af862",
+            client_ip: Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 23))),
+            client_asn: Some(64497),
+        }
+    }
+
+    fn router(config: &str) -> Router {
+        let config: Config = toml::from_str(config).expect("valid config");
+        Router::new(&config).expect("valid routes")
+    }
+
+    const CONFIG: &str = r#"
+        [frontends.https]
+        listen-address = "[::]:443"
+
+        [backends.default]
+        addresses = []
+
+        [backends.h2]
+        addresses = []
+
+        [[routes]]
+        name = "scraper"
+        ja4-prefix = ["t13d1516h2_8daaf6152771"]
+        client-asns = [64496]
+        action = "tarpit"
+
+        [[routes]]
+        name = "legacy"
+        tls-versions = ["1.0", "1.1"]
+        action = "reject"
+        alert = "protocol-version"
+
+        [[routes]]
+        name = "h2"
+        sni = ["*.example.com"]
+        alpn = ["h2"]
+        client-cidrs = ["203.0.113.0/24"]
+        action = "forward"
+        backend = "h2"
+
+        [[routes]]
+        sni = ["*"]
+        action = "forward"
+        backend = "default"
+    "#;
+
+    #[test]
+    fn test_first_match() {
+        let router = router(CONFIG);
+
+        assert_eq!(router.route(&input()).unwrap().name(), "h2");
+        assert_eq!(
+            router
+                .route(&RouteInput {
+                    client_asn: Some(64496),
+                    ..input()
+                })
+                .unwrap()
+                .name(),
+            "scraper"
+        );
+        assert_eq!(
+            router
+                .route(&RouteInput {
+                    tls_version: 0x0302,
+                    ..input()
+                })
+                .unwrap()
+                .name(),
+            "legacy"
+        );
+        assert_eq!(
+            router
+                .route(&RouteInput {
+                    alpn: &[b"http/1.1"],
+                    ..input()
+                })
+                .unwrap()
+                .name(),
+            "#3"
+        );
+        assert!(
+            router
+                .route(&RouteInput {
+                    sni: None,
+                    ..input()
+                })
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_unknown_backend() {
+        let config: Config = toml::from_str(
+            r#"
+            [frontends.https]
+            listen-address = "[::]:443"
+
+            [backends]
+
+            [[routes]]
+            action = "forward"
+            backend = "missing"
+            "#,
+        )
+        .unwrap();
+        assert!(Router::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_alpn_target_is_not_selected_by_sni() {
+        let config: Config = toml::from_str(
+            r#"
+            [frontends.https]
+            listen-address = "[::]:443"
+
+            [backends."example.com"]
+            addresses = ["192.0.2.1:443"]
+
+            [[backends."example.com".alpn]]
+            protocols = ["acme-tls/1"]
+            backend = "acme"
+
+            [backends.acme]
+            addresses = ["192.0.2.2:443"]
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(&config).unwrap();
+        let backends = &config.backends;
+        assert_eq!(
+            router.backend_by_sni(backends, "example.com", &[b"acme-tls/1"]),
+            Some("acme")
+        );
+        assert_eq!(
+            router.backend_by_sni(backends, "example.com", &[b"h2"]),
+            Some("example.com")
+        );
+        assert_eq!(
+            router.backend_by_sni(backends, "acme", &[b"acme-tls/1"]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sni_matches() {
+        assert!(sni_matches("*", "example.com"));
+        assert!(sni_matches("example.com", "Example.COM"));
+        assert!(sni_matches("example.com.", "example.com"));
+        assert!(!sni_matches("example.com", "www.example.com"));
+        assert!(sni_matches("*.example.com", "www.example.com"));
+        assert!(sni_matches("*.example.com", "a.b.example.com"));
+        assert!(!sni_matches("*.example.com", "example.com"));
+        assert!(!sni_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn test_alpn_matches() {
+        let protocols = ["h2".to_string(), "acme-tls/1".to_string()];
+
+        assert!(alpn_matches(
+            AlpnMatch::First,
+            &protocols,
+            &[b"h2", b"http/1.1"]
+        ));
+        assert!(!alpn_matches(
+            AlpnMatch::First,
+            &protocols,
+            &[b"http/1.1", b"h2"]
+        ));
+        assert!(alpn_matches(
+            AlpnMatch::Any,
+            &protocols,
+            &[b"http/1.1", b"h2"]
+        ));
+        assert!(alpn_matches(AlpnMatch::Any, &protocols, &[b"acme-tls/1"]));
+        assert!(!alpn_matches(AlpnMatch::Any, &protocols, &[b"dot"]));
+        assert!(!alpn_matches(AlpnMatch::First, &protocols, &[]));
+    }
+}
@@ -0,0 +1,627 @@
+use std::{
+    ffi::CString,
+    fs::{self, Permissions},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::{
+        fd::OwnedFd,
+        unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    },
+    path::Path,
+    ptr,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use socket2::{Domain, SockAddr, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener};
+
+use crate::{
+    config::{Backend, Frontend, ListenAddress, SocketOptions, UnixSocket},
+    stream::Stream,
+};
+
+/// Idle time before keepalive probes are sent on backend connections if not configured
+const DEFAULT_BACKEND_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// `IP_LOCAL_PORT_RANGE` (Linux 6.3), not exported by libc yet
+#[cfg(target_os = "linux")]
+const IP_LOCAL_PORT_RANGE: libc::c_int = 51;
+
+/// Opens backend connections from the configured source address, interface and ports
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    source_addresses: Vec<IpAddr>,
+    bind_interface: Option<String>,
+    fwmark: Option<u32>,
+    source_port_range: Option<(u16, u16)>,
+    /// Bind to a foreign address, the address of the client
+    transparent: bool,
+    options: SocketOptions,
+    fastopen: bool,
+}
+
+impl Connector {
+    pub fn new(config: &Backend) -> Result<Self> {
+        let source_addresses: Vec<IpAddr> = config
+            .source_address
+            .iter()
+            .map(|ip| ip.to_canonical())
+            .collect();
+        for family_is_ipv4 in [true, false] {
+            if source_addresses
+                .iter()
+                .filter(|ip| ip.is_ipv4() == family_is_ipv4)
+                .count()
+                > 1
+            {
+                bail!("only one source address per address family is allowed");
+            }
+        }
+        let source_port_range = match config.source_port_range {
+            Some([first, last]) if first == 0 || first > last => {
+                bail!("invalid source port range {first}-{last}")
+            }
+            Some([first, last]) => Some((first, last)),
+            None => None,
+        };
+        if cfg!(not(target_os = "linux"))
+            && (config.bind_interface.is_some()
+                || config.fwmark.is_some()
+                || source_port_range.is_some()
+                || config.transparent)
+        {
+            bail!(
+                "bind-interface, fwmark, source-port-range and transparent are only supported on Linux"
+            );
+        }
+        check_supported(&config.socket_options)?;
+        if cfg!(not(target_os = "linux")) && config.fastopen {
+            bail!("fastopen is only supported on Linux");
+        }
+        if config.transparent && !source_addresses.is_empty() {
+            bail!("transparent backends connect from the client address, not a source address");
+        }
+
+        Ok(Self {
+            source_addresses,
+            bind_interface: config.bind_interface.clone(),
+            fwmark: config.fwmark,
+            source_port_range,
+            transparent: false,
+            options: config.socket_options.clone(),
+            fastopen: config.fastopen,
+        })
+    }
+
+    /// A connector binding to the address of a client, which does not have to be local
+    pub fn for_client(&self, client_ip: IpAddr) -> Self {
+        Self {
+            source_addresses: vec![client_ip.to_canonical()],
+            transparent: true,
+            ..self.clone()
+        }
+    }
+
+    /// The source address of the family of `addr`, `None` if the kernel should choose
+    fn source_address(&self, addr: SocketAddr) -> io::Result<Option<IpAddr>> {
+        if self.source_addresses.is_empty() {
+            return Ok(None);
+        }
+        self.source_addresses
+            .iter()
+            .copied()
+            .find(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .map(Some)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("no source address configured for {addr}"),
+                )
+            })
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        let source_address = self.source_address(addr)?;
+        let socket_ref = SockRef::from(&socket);
+        socket_ref.set_nodelay(true)?;
+        apply_options(&socket_ref, &self.options, Some(DEFAULT_BACKEND_KEEPALIVE))?;
+        self.configure(&socket_ref, addr, source_address.is_some())?;
+        if let Some(ip) = source_address {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        socket.connect(addr).await
+    }
+
+    #[cfg(target_os = "linux")]
+    fn configure(&self, socket: &SockRef<'_>, addr: SocketAddr, binds: bool) -> io::Result<()> {
+        if self.transparent {
+            set_transparent(socket, addr)?;
+        }
+        if let Some(interface) = &self.bind_interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(mark) = self.fwmark {
+            socket.set_mark(mark)?;
+        }
+        if binds {
+            // choose the port on connect, so connections to different backends can share ports
+            set_option(socket, libc::IPPROTO_IP, libc::IP_BIND_ADDRESS_NO_PORT, 1)?;
+        }
+        if let Some((first, last)) = self.source_port_range {
+            let range = u32::from(first) | (u32::from(last) << 16);
+            set_option(socket, libc::IPPROTO_IP, IP_LOCAL_PORT_RANGE, range)?;
+        }
+        if self.fastopen {
+            set_option(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[allow(clippy::unused_self, clippy::unnecessary_wraps)]
+    fn configure(&self, _socket: &SockRef<'_>, _addr: SocketAddr, _binds: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Listen socket of a frontend
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => Ok(Stream::Tcp(listener.accept().await?.0)),
+            Self::Unix(listener) => Ok(Stream::Unix(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Binds the listen sockets of a frontend, more than one if `reuse-port` is set
+///
+/// Accepted connections inherit the socket options of the listen socket.
+pub fn listen(frontend: &Frontend) -> Result<Vec<Listener>> {
+    let addr = match &frontend.listen_address {
+        ListenAddress::Tcp(addr) => *addr,
+        ListenAddress::Unix(path) => {
+            if frontend.transparent
+                || frontend.defer_accept_secs.is_some()
+                || frontend.fastopen_queue.is_some()
+                || frontend.reuse_port.is_some()
+                || frontend.socket_options != SocketOptions::default()
+            {
+                bail!(
+                    "transparent, defer-accept-secs, fastopen-queue, reuse-port and socket-options \
+                     need a TCP listen address"
+                );
+            }
+            let listener = listen_unix(path, frontend.backlog, &frontend.unix_socket)
+                .with_context(|| format!("failed listening on {path:?}"))?;
+            return Ok(vec![Listener::Unix(listener)]);
+        }
+    };
+    check_supported(&frontend.socket_options)?;
+    if cfg!(not(target_os = "linux"))
+        && (frontend.transparent
+            || frontend.defer_accept_secs.is_some()
+            || frontend.fastopen_queue.is_some())
+    {
+        bail!("transparent, defer-accept-secs and fastopen-queue are only supported on Linux");
+    }
+    let count = match frontend.reuse_port {
+        Some(0) => bail!("reuse-port needs at least one listen socket"),
+        Some(count) => count,
+        None => 1,
+    };
+    (0..count)
+        .map(|_| listen_socket(frontend, addr).map(Listener::Tcp))
+        .collect()
+}
+
+fn listen_socket(frontend: &Frontend, addr: SocketAddr) -> Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if frontend.reuse_port.is_some() {
+        socket.set_reuseport(true)?;
+    }
+    let socket_ref = SockRef::from(&socket);
+    apply_options(&socket_ref, &frontend.socket_options, None)?;
+    #[cfg(target_os = "linux")]
+    {
+        if frontend.transparent {
+            set_transparent(&socket_ref, addr)
+                .context("failed enabling IP_TRANSPARENT, CAP_NET_ADMIN is needed")?;
+        }
+        if let Some(secs) = frontend.defer_accept_secs {
+            set_option(&socket_ref, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, secs)?;
+        }
+        if let Some(queue) = frontend.fastopen_queue {
+            set_option(&socket_ref, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue)?;
+        }
+    }
+    socket.bind(addr)?;
+    Ok(socket.listen(frontend.backlog)?)
+}
+
+/// Binds a Unix socket, replacing the socket file of a previous run
+///
+/// The socket is bound in a private directory and only moved to `path`
+/// after its mode and owner are applied, so nobody can connect before.
+fn listen_unix(path: &Path, backlog: u32, options: &UnixSocket) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => bail!("{path:?} exists and is not a socket"),
+        Err(_) => {}
+    }
+    let file_name = path.file_name().context("socket path has no file name")?;
+    let private_dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    // left by a previous run with the same process ID
+    let _ = fs::remove_dir_all(&private_dir);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .with_context(|| format!("failed creating {private_dir:?}"))?;
+    let listener = bind_unix(&private_dir.join(file_name), path, backlog, options);
+    let _ = fs::remove_dir_all(&private_dir);
+    listener
+}
+
+/// Binds a Unix socket at `private_path`, applies mode and owner and moves it to `path`
+fn bind_unix(
+    private_path: &Path,
+    path: &Path,
+    backlog: u32,
+    options: &UnixSocket,
+) -> Result<UnixListener> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(private_path)?)?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    socket.set_nonblocking(true)?;
+
+    if let Some(mode) = options.mode {
+        fs::set_permissions(private_path, Permissions::from_mode(mode))?;
+    }
+    if options.owner.is_some() || options.group.is_some() {
+        let owner = options.owner.as_deref().map(user_id).transpose()?;
+        let group = options.group.as_deref().map(group_id).transpose()?;
+        std::os::unix::fs::chown(private_path, owner, group)
+            .context("failed changing owner of socket file")?;
+    }
+    fs::rename(private_path, path).context("failed moving socket file")?;
+    Ok(UnixListener::from_std(OwnedFd::from(socket).into())?)
+}
+
+/// ID of a user given by name or ID
+fn user_id(user: &str) -> Result<u32> {
+    if let Ok(id) = user.parse() {
+        return Ok(id);
+    }
+    let name = CString::new(user)?;
+    // SAFETY: the name is a valid C string, the entry is only read before the next call
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("unknown user {user:?}");
+    }
+    // SAFETY: the entry is not null
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+/// ID of a group given by name or ID
+fn group_id(group: &str) -> Result<u32> {
+    if let Ok(id) = group.parse() {
+        return Ok(id);
+    }
+    let name = CString::new(group)?;
+    // SAFETY: the name is a valid C string, the entry is only read before the next call
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("unknown group {group:?}");
+    }
+    // SAFETY: the entry is not null
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Addresses of all network interfaces of the host
+pub fn local_addresses() -> io::Result<Vec<IpAddr>> {
+    let mut interfaces = ptr::null_mut();
+    // SAFETY: the list is freed below and not used afterwards
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addresses = Vec::new();
+    let mut entry = interfaces;
+    while !entry.is_null() {
+        // SAFETY: entries are valid until the list is freed
+        let interface = unsafe { &*entry };
+        // SAFETY: the address is null or a socket address of its family
+        if let Some(ip) = unsafe { sockaddr_ip(interface.ifa_addr) } {
+            addresses.push(ip);
+        }
+        entry = interface.ifa_next;
+    }
+    // SAFETY: the list was allocated by getifaddrs
+    unsafe { libc::freeifaddrs(interfaces) };
+    Ok(addresses)
+}
+
+/// IP address of an `AF_INET` or `AF_INET6` socket address
+///
+/// # Safety
+/// `addr` must be null or point to a socket address matching its `sa_family`.
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    // SAFETY: guaranteed by the caller
+    unsafe {
+        match i32::from((*addr).sa_family) {
+            libc::AF_INET => {
+                let addr = &*addr.cast::<libc::sockaddr_in>();
+                Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+            }
+            libc::AF_INET6 => {
+                let addr = &*addr.cast::<libc::sockaddr_in6>();
+                Some(Ipv6Addr::from(addr.sin6_addr.s6_addr).into())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn check_supported(options: &SocketOptions) -> Result<()> {
+    if cfg!(not(target_os = "linux"))
+        && (options.user_timeout_ms.is_some()
+            || options.notsent_lowat.is_some()
+            || options.congestion_control.is_some())
+    {
+        bail!("user-timeout-ms, notsent-lowat and congestion-control are only supported on Linux");
+    }
+    Ok(())
+}
+
+/// Sets the configured options, keepalive is enabled if any keepalive option is set
+fn apply_options(
+    socket: &SockRef<'_>,
+    options: &SocketOptions,
+    default_keepalive: Option<Duration>,
+) -> io::Result<()> {
+    let keepalive_time = options
+        .keepalive_time_secs
+        .map(Duration::from_secs)
+        .or(default_keepalive);
+    if keepalive_time.is_some()
+        || options.keepalive_interval_secs.is_some()
+        || options.keepalive_count.is_some()
+    {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(time) = keepalive_time {
+            keepalive = keepalive.with_time(time);
+        }
+        if let Some(interval) = options.keepalive_interval_secs {
+            keepalive = keepalive.with_interval(Duration::from_secs(interval));
+        }
+        if let Some(count) = options.keepalive_count {
+            keepalive = keepalive.with_retries(count);
+        }
+        socket.set_keepalive(true)?;
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(timeout) = options.user_timeout_ms {
+            socket.set_tcp_user_timeout(Some(Duration::from_millis(timeout)))?;
+        }
+        if let Some(lowat) = options.notsent_lowat {
+            set_option(socket, libc::IPPROTO_TCP, libc::TCP_NOTSENT_LOWAT, lowat)?;
+        }
+        if let Some(algorithm) = &options.congestion_control {
+            socket.set_tcp_congestion(algorithm.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Allows binding to and accepting connections for addresses that are not local
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &SockRef<'_>, addr: SocketAddr) -> io::Result<()> {
+    if addr.is_ipv4() {
+        socket.set_ip_transparent(true)
+    } else {
+        set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TRANSPARENT, 1)
+    }
+}
+
+/// Sets a socket option socket2 has no setter for
+#[cfg(target_os = "linux")]
+pub fn set_option<T>(
+    socket: &SockRef<'_>,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    #[allow(clippy::cast_possible_truncation)]
+    let len = size_of::<T>() as libc::socklen_t;
+    // SAFETY: the pointer and length describe `value`, which outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&raw const value).cast(),
+            len,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn connector(config: &str) -> Result<Connector> {
+        Connector::new(&toml::from_str(config).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dual_stack = connector(r#"source-address = ["127.0.0.2", "::1"]"#).unwrap();
+        let _stream = dual_stack.connect(addr).await.unwrap();
+        let (_accepted, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        let ipv6_only = connector(r#"source-address = ["::1"]"#).unwrap();
+        let err = ipv6_only.connect(addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_source_port_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port_range = connector("source-port-range = [40000, 40009]").unwrap();
+        let _stream = port_range
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_accepted, peer) = listener.accept().await.unwrap();
+        assert!((40000..=40009).contains(&peer.port()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_listen_options() {
+        let frontend: Frontend = toml::from_str(
+            r#"
+            listen-address = "127.0.0.1:0"
+            reuse-port = 2
+            backlog = 16
+            defer-accept-secs = 5
+
+            [socket-options]
+            keepalive-time-secs = 60
+            keepalive-count = 3
+            user-timeout-ms = 10000
+            notsent-lowat = 16384
+            "#,
+        )
+        .unwrap();
+        let listeners = listen(&frontend).unwrap();
+        assert_eq!(listeners.len(), 2);
+
+        let Listener::Tcp(listener) = &listeners[0] else {
+            panic!("listener is not TCP");
+        };
+        let listener = SockRef::from(listener);
+        assert!(listener.keepalive().unwrap());
+        assert_eq!(listener.keepalive_time().unwrap(), Duration::from_secs(60));
+        assert_eq!(listener.keepalive_retries().unwrap(), 3);
+        assert_eq!(
+            listener.tcp_user_timeout().unwrap(),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backend_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = Connector::default()
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let stream = SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(stream.keepalive().unwrap());
+        assert_eq!(stream.keepalive_time().unwrap(), DEFAULT_BACKEND_KEEPALIVE);
+    }
+
+    #[tokio::test]
+    async fn test_listen_unix() {
+        let path = std::env::temp_dir().join(format!("tlslb-test-{}.sock", std::process::id()));
+        let frontend: Frontend = toml::from_str(&format!(
+            r#"
+            listen-address = "unix:{}"
+
+            [unix-socket]
+            mode = 0o600
+            group = "{}"
+            "#,
+            path.display(),
+            // SAFETY: getgid has no preconditions
+            unsafe { libc::getgid() },
+        ))
+        .unwrap();
+        // a stale socket file is replaced
+        drop(listen(&frontend).unwrap());
+        let listeners = listen(&frontend).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let accepted = listeners[0].accept().await.unwrap();
+        assert!(matches!(accepted, Stream::Unix(_)));
+        fs::remove_file(&path).unwrap();
+        // the private directory is removed
+        let private_dir = path.with_file_name(format!(
+            ".{}.{}",
+            path.file_name().unwrap().to_string_lossy(),
+            std::process::id()
+        ));
+        assert!(!private_dir.exists());
+
+        fs::write(&path, b"").unwrap();
+        assert!(listen(&frontend).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_local_addresses() {
+        let addresses = local_addresses().unwrap();
+        assert!(
+            addresses.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            "{addresses:?}"
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(connector(r#"source-address = ["192.0.2.1", "192.0.2.2"]"#).is_err());
+        assert!(connector("source-port-range = [50000, 40000]").is_err());
+        assert!(connector("source-port-range = [0, 40000]").is_err());
+        assert!(connector("transparent = true\nsource-address = [\"192.0.2.1\"]").is_err());
+    }
+}
@@ -0,0 +1,871 @@
+use std::{fmt::Write as _, str, time::Duration};
+
+use anyhow::{Context, Result, bail, ensure};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest command, reply line or XML element of the plaintext phase
+const MAX_LEN: usize = 8192;
+
+/// Tag of the IMAP command sent to backends
+const IMAP_TAG: &str = "tlslb";
+
+const XMPP_TLS_NAMESPACE: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+
+/// `SSLRequest` message of the PostgreSQL protocol, length and request code
+const POSTGRES_SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+
+/// `GSSENCRequest` message of the PostgreSQL protocol, length and request code
+const POSTGRES_GSSENC_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x30];
+
+/// Protocols upgraded to TLS on the same connection
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Protocol {
+    /// SMTP with `STARTTLS` (RFC 3207)
+    Smtp,
+    /// IMAP with `STARTTLS` (RFC 9051)
+    Imap,
+    /// POP3 with `STLS` (RFC 2595)
+    Pop3,
+    /// XMPP with `<starttls/>` (RFC 6120)
+    Xmpp,
+    /// PostgreSQL with `SSLRequest`
+    Postgres,
+}
+
+/// The plaintext phase of a client until it started TLS
+///
+/// The client talked to tlslb, the backend has not seen anything yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Preamble {
+    protocol: Protocol,
+    /// Data of the client repeated to the backend,
+    /// the `EHLO` command of SMTP or the stream header of XMPP
+    replay: Vec<u8>,
+    timeout: Duration,
+}
+
+/// Buffers reads until a complete command or reply was received
+#[derive(Default)]
+struct Reader {
+    buffer: Vec<u8>,
+}
+
+impl Reader {
+    /// Reads until `find` returns the end of a message and removes it from the buffer
+    async fn read_until(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+        find: impl Fn(&[u8]) -> Option<usize>,
+    ) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = find(&self.buffer) {
+                return Ok(Some(self.buffer.drain(..end).collect()));
+            }
+            if self.buffer.len() >= MAX_LEN {
+                bail!("plaintext message is longer than {MAX_LEN} bytes");
+            }
+            let mut chunk = [0u8; 1024];
+            let len = stream.read(&mut chunk).await?;
+            if len == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    /// Reads a line ending with LF, the line ending is removed
+    async fn read_line(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<String>> {
+        let Some(line) = self
+            .read_until(stream, |buffer| find(buffer, b"\n").map(|end| end + 1))
+            .await?
+        else {
+            return Ok(None);
+        };
+        let line = str::from_utf8(&line).context("line is not UTF-8")?;
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    /// Reads a line, failing if the peer closed the connection
+    async fn expect_line(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+        self.read_line(stream)
+            .await?
+            .context("connection closed during plaintext phase")
+    }
+
+    /// Reads a possibly multiline SMTP reply and returns its code
+    async fn read_smtp_reply(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> Result<u16> {
+        loop {
+            let line = self.expect_line(stream).await?;
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .with_context(|| format!("invalid SMTP reply {line:?}"))?;
+            // the last line of a reply has a space after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(code);
+            }
+        }
+    }
+
+    /// Makes sure the peer did not send anything that must not be sent before TLS
+    fn finish(self) -> Result<()> {
+        ensure!(
+            self.buffer.is_empty(),
+            "peer sent data before TLS was started"
+        );
+        Ok(())
+    }
+}
+
+/// Offset of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Offset after the end of the tag following `start`, for elements of XMPP streams
+fn find_element_end(buffer: &[u8], start: &[u8]) -> Option<usize> {
+    let start = find(buffer, start)?;
+    let end = buffer[start..].iter().position(|&byte| byte == b'>')?;
+    Some(start + end + 1)
+}
+
+/// Value of an attribute of an XML tag, quoted with `'` or `"`
+fn xml_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    tag.split_ascii_whitespace().find_map(|attribute| {
+        let value = attribute.strip_prefix(name)?.strip_prefix('=')?;
+        let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+        value[1..].split(quote).next()
+    })
+}
+
+/// Splits a command into its uppercase verb and the rest
+fn split_command(line: &str) -> (String, &str) {
+    let (verb, argument) = line.split_once(' ').unwrap_or((line, ""));
+    (verb.to_ascii_uppercase(), argument)
+}
+
+/// Answers the client like a server until it starts TLS
+///
+/// Returns `None` if the client quit before.
+pub async fn accept(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    protocol: Protocol,
+    hostname: &str,
+    timeout: Duration,
+) -> Result<Option<Preamble>> {
+    let replay = tokio::time::timeout(timeout, async {
+        match protocol {
+            Protocol::Smtp => accept_smtp(stream, hostname).await,
+            Protocol::Imap => accept_imap(stream, hostname)
+                .await
+                .map(|done| done.then(Vec::new)),
+            Protocol::Pop3 => accept_pop3(stream, hostname)
+                .await
+                .map(|done| done.then(Vec::new)),
+            Protocol::Xmpp => accept_xmpp(stream, hostname).await,
+            Protocol::Postgres => accept_postgres(stream)
+                .await
+                .map(|done| done.then(Vec::new)),
+        }
+    })
+    .await
+    .context("timeout of plaintext phase of client")??;
+    Ok(replay.map(|replay| Preamble {
+        protocol,
+        replay,
+        timeout,
+    }))
+}
+
+async fn accept_smtp(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    hostname: &str,
+) -> Result<Option<Vec<u8>>> {
+    let mut reader = Reader::default();
+    stream
+        .write_all(format!("220 {hostname} ESMTP\r\n").as_bytes())
+        .await?;
+    let mut hello = None;
+    while let Some(line) = reader.read_line(stream).await? {
+        let (verb, _argument) = split_command(&line);
+        let reply = match verb.as_str() {
+            "EHLO" => {
+                hello = Some(format!("{line}\r\n"));
+                format!("250-{hostname}\r\n250 STARTTLS\r\n")
+            }
+            "HELO" => {
+                hello = Some(format!("{line}\r\n"));
+                format!("250 {hostname}\r\n")
+            }
+            "NOOP" | "RSET" => "250 2.0.0 OK\r\n".to_string(),
+            "QUIT" => {
+                stream.write_all(b"221 2.0.0 Bye\r\n").await?;
+                return Ok(None);
+            }
+            "STARTTLS" => match hello.take() {
+                Some(hello) => {
+                    stream
+                        .write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                        .await?;
+                    reader.finish()?;
+                    return Ok(Some(hello.into_bytes()));
+                }
+                None => "503 5.5.1 Send EHLO first\r\n".to_string(),
+            },
+            _ => "530 5.7.0 Must issue a STARTTLS command first\r\n".to_string(),
+        };
+        stream.write_all(reply.as_bytes()).await?;
+    }
+    Ok(None)
+}
+
+/// Returns whether the client started TLS
+async fn accept_imap(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    hostname: &str,
+) -> Result<bool> {
+    const CAPABILITIES: &str = "IMAP4rev1 IMAP4rev2 STARTTLS LOGINDISABLED";
+
+    let mut reader = Reader::default();
+    stream
+        .write_all(format!("* OK [CAPABILITY {CAPABILITIES}] {hostname} ready\r\n").as_bytes())
+        .await?;
+    while let Some(line) = reader.read_line(stream).await? {
+        let Some((tag, command)) = line.split_once(' ') else {
+            stream.write_all(b"* BAD Missing command\r\n").await?;
+            continue;
+        };
+        let (verb, _argument) = split_command(command);
+        let reply = match verb.as_str() {
+            "CAPABILITY" => {
+                format!("* CAPABILITY {CAPABILITIES}\r\n{tag} OK CAPABILITY completed\r\n")
+            }
+            "NOOP" => format!("{tag} OK NOOP completed\r\n"),
+            "LOGOUT" => {
+                let reply =
+                    format!("* BYE {hostname} logging out\r\n{tag} OK LOGOUT completed\r\n");
+                stream.write_all(reply.as_bytes()).await?;
+                return Ok(false);
+            }
+            "STARTTLS" => {
+                let reply = format!("{tag} OK Begin TLS negotiation now\r\n");
+                stream.write_all(reply.as_bytes()).await?;
+                reader.finish()?;
+                return Ok(true);
+            }
+            _ => format!("{tag} BAD Must issue a STARTTLS command first\r\n"),
+        };
+        stream.write_all(reply.as_bytes()).await?;
+    }
+    Ok(false)
+}
+
+/// Returns whether the client started TLS
+async fn accept_pop3(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    hostname: &str,
+) -> Result<bool> {
+    let mut reader = Reader::default();
+    stream
+        .write_all(format!("+OK {hostname} ready\r\n").as_bytes())
+        .await?;
+    while let Some(line) = reader.read_line(stream).await? {
+        let (verb, _argument) = split_command(&line);
+        let reply: &[u8] = match verb.as_str() {
+            "CAPA" => b"+OK Capability list follows\r\nSTLS\r\n.\r\n",
+            "NOOP" => b"+OK\r\n",
+            "QUIT" => {
+                stream.write_all(b"+OK Bye\r\n").await?;
+                return Ok(false);
+            }
+            "STLS" => {
+                stream.write_all(b"+OK Begin TLS negotiation\r\n").await?;
+                reader.finish()?;
+                return Ok(true);
+            }
+            _ => b"-ERR Must issue a STLS command first\r\n",
+        };
+        stream.write_all(reply).await?;
+    }
+    Ok(false)
+}
+
+async fn accept_xmpp(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    hostname: &str,
+) -> Result<Option<Vec<u8>>> {
+    let mut reader = Reader::default();
+    let Some(header) = reader
+        .read_until(stream, |buffer| find_element_end(buffer, b"<stream:stream"))
+        .await?
+    else {
+        return Ok(None);
+    };
+    let tag = str::from_utf8(&header).context("stream header is not UTF-8")?;
+    let namespace = xml_attribute(tag, "xmlns").unwrap_or("jabber:client");
+    let domain = xml_attribute(tag, "to").unwrap_or(hostname);
+
+    let mut id = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| anyhow::anyhow!("failed generating stream id"))?;
+    let id = id.iter().fold(String::new(), |mut id, byte| {
+        let _ = write!(id, "{byte:02x}");
+        id
+    });
+    let response = format!(
+        "<?xml version='1.0'?><stream:stream xmlns='{namespace}' \
+         xmlns:stream='http://etherx.jabber.org/streams' id='{id}' from='{domain}' version='1.0'>\
+         <stream:features><starttls xmlns='{XMPP_TLS_NAMESPACE}'><required/></starttls></stream:features>"
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    let Some(element) = reader
+        .read_until(stream, |buffer| find_element_end(buffer, b"<"))
+        .await?
+    else {
+        return Ok(None);
+    };
+    if find(&element, b"<starttls").is_none() {
+        stream
+            .write_all(
+                b"<stream:error><policy-violation xmlns='urn:ietf:params:xml:ns:xmpp-streams'/>\
+                  </stream:error></stream:stream>",
+            )
+            .await?;
+        bail!("XMPP client did not start TLS");
+    }
+    // the element is either empty or closed by a separate tag
+    if !element.ends_with(b"/>") {
+        reader
+            .read_until(stream, |buffer| find_element_end(buffer, b"</starttls"))
+            .await?
+            .context("connection closed during plaintext phase")?;
+    }
+    stream
+        .write_all(format!("<proceed xmlns='{XMPP_TLS_NAMESPACE}'/>").as_bytes())
+        .await?;
+    reader.finish()?;
+    Ok(Some(header))
+}
+
+/// Returns whether the client started TLS
+///
+/// A `GSSENCRequest` is declined, so the client sends an `SSLRequest` next.
+/// Clients not requesting encryption get an error.
+async fn accept_postgres(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> Result<bool> {
+    let mut reader = Reader::default();
+    loop {
+        let Some(request) = reader
+            .read_until(stream, |buffer| (buffer.len() >= 8).then_some(8))
+            .await?
+        else {
+            return Ok(false);
+        };
+        if request == POSTGRES_GSSENC_REQUEST {
+            stream.write_all(b"N").await?;
+            continue;
+        }
+        if request == POSTGRES_SSL_REQUEST {
+            stream.write_all(b"S").await?;
+            reader.finish()?;
+            return Ok(true);
+        }
+        stream
+            .write_all(&postgres_error("28000", "TLS is required"))
+            .await?;
+        return Ok(false);
+    }
+}
+
+/// `ErrorResponse` message of the PostgreSQL protocol
+fn postgres_error(code: &str, message: &str) -> Vec<u8> {
+    let mut fields = Vec::new();
+    for (field, value) in [
+        (b'S', "FATAL"),
+        (b'V', "FATAL"),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        fields.push(field);
+        fields.extend_from_slice(value.as_bytes());
+        fields.push(0);
+    }
+    fields.push(0);
+    let len = u32::try_from(fields.len() + 4).unwrap_or(u32::MAX);
+    [&[b'E'][..], &len.to_be_bytes(), &fields].concat()
+}
+
+impl Preamble {
+    /// Plaintext phase of PostgreSQL clients that start with the client hello
+    ///
+    /// The backend still gets an `SSLRequest`, so it does not need to support direct TLS.
+    pub const fn postgres_direct(timeout: Duration) -> Self {
+        Self {
+            protocol: Protocol::Postgres,
+            replay: Vec::new(),
+            timeout,
+        }
+    }
+
+    /// Brings a new backend connection to the state the client is in
+    ///
+    /// If TLS is forwarded, the plaintext phase is replayed and TLS is started on the backend.
+    /// If tlslb terminates TLS itself, the backend only gets the plaintext of the TLS session,
+    /// so only its greeting is skipped.
+    pub async fn connect(
+        &self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        terminating: bool,
+    ) -> Result<()> {
+        tokio::time::timeout(self.timeout, async {
+            match self.protocol {
+                Protocol::Smtp => self.connect_smtp(stream, terminating).await,
+                Protocol::Imap => Self::connect_imap(stream, terminating).await,
+                Protocol::Pop3 => Self::connect_pop3(stream, terminating).await,
+                Protocol::Xmpp => self.connect_xmpp(stream, terminating).await,
+                Protocol::Postgres => Self::connect_postgres(stream, terminating).await,
+            }
+        })
+        .await
+        .context("timeout of plaintext phase of backend")?
+    }
+
+    async fn connect_smtp(
+        &self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        terminating: bool,
+    ) -> Result<()> {
+        let mut reader = Reader::default();
+        let code = reader.read_smtp_reply(stream).await?;
+        ensure!(code == 220, "backend greeted with SMTP reply {code}");
+        if terminating {
+            return reader.finish();
+        }
+        stream.write_all(&self.replay).await?;
+        let code = reader.read_smtp_reply(stream).await?;
+        ensure!(code == 250, "backend answered EHLO with SMTP reply {code}");
+        stream.write_all(b"STARTTLS\r\n").await?;
+        let code = reader.read_smtp_reply(stream).await?;
+        ensure!(
+            code == 220,
+            "backend answered STARTTLS with SMTP reply {code}"
+        );
+        reader.finish()
+    }
+
+    async fn connect_imap(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        terminating: bool,
+    ) -> Result<()> {
+        let mut reader = Reader::default();
+        let greeting = reader.expect_line(stream).await?;
+        ensure!(
+            greeting.starts_with("* OK"),
+            "backend greeted with {greeting:?}"
+        );
+        if terminating {
+            return reader.finish();
+        }
+        stream
+            .write_all(format!("{IMAP_TAG} STARTTLS\r\n").as_bytes())
+            .await?;
+        // untagged responses may come before the tagged one
+        loop {
+            let line = reader.expect_line(stream).await?;
+            if let Some(status) = line
+                .strip_prefix(IMAP_TAG)
+                .and_then(|rest| rest.strip_prefix(' '))
+            {
+                ensure!(
+                    status.starts_with("OK"),
+                    "backend answered STARTTLS with {line:?}"
+                );
+                return reader.finish();
+            }
+        }
+    }
+
+    async fn connect_pop3(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        terminating: bool,
+    ) -> Result<()> {
+        let mut reader = Reader::default();
+        let greeting = reader.expect_line(stream).await?;
+        ensure!(
+            greeting.starts_with("+OK"),
+            "backend greeted with {greeting:?}"
+        );
+        if terminating {
+            return reader.finish();
+        }
+        stream.write_all(b"STLS\r\n").await?;
+        let line = reader.expect_line(stream).await?;
+        ensure!(
+            line.starts_with("+OK"),
+            "backend answered STLS with {line:?}"
+        );
+        reader.finish()
+    }
+
+    async fn connect_xmpp(
+        &self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        terminating: bool,
+    ) -> Result<()> {
+        // XMPP servers only speak after the stream header, which the client repeats after TLS
+        if terminating {
+            return Ok(());
+        }
+        let mut reader = Reader::default();
+        stream.write_all(&self.replay).await?;
+        let features = reader
+            .read_until(stream, |buffer| {
+                find(buffer, b"</stream:features>").map(|end| end + b"</stream:features>".len())
+            })
+            .await?
+            .context("connection closed during plaintext phase")?;
+        ensure!(
+            find(&features, XMPP_TLS_NAMESPACE.as_bytes()).is_some(),
+            "backend does not offer STARTTLS"
+        );
+        stream
+            .write_all(format!("<starttls xmlns='{XMPP_TLS_NAMESPACE}'/>").as_bytes())
+            .await?;
+        let answer = reader
+            .read_until(stream, |buffer| find_element_end(buffer, b"<"))
+            .await?
+            .context("connection closed during plaintext phase")?;
+        ensure!(
+            find(&answer, b"<proceed").is_some(),
+            "backend answered STARTTLS with {:?}",
+            String::from_utf8_lossy(&answer)
+        );
+        if !answer.ends_with(b"/>") {
+            reader
+                .read_until(stream, |buffer| find_element_end(buffer, b"</proceed"))
+                .await?
+                .context("connection closed during plaintext phase")?;
+        }
+        reader.finish()
+    }
+
+    /// Sends an `SSLRequest` and discards the answer of the backend
+    async fn connect_postgres(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        terminating: bool,
+    ) -> Result<()> {
+        // PostgreSQL servers only speak after the startup message, which the client sends in TLS
+        if terminating {
+            return Ok(());
+        }
+        stream.write_all(&POSTGRES_SSL_REQUEST).await?;
+        let mut answer = [0u8];
+        stream.read_exact(&mut answer).await?;
+        match answer {
+            [b'S'] => Ok(()),
+            [b'N'] => bail!("backend does not support TLS"),
+            [other] => bail!("backend answered SSLRequest with {other:#04x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncBufReadExt, BufReader, duplex};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Plays a peer: writes each line, then reads the given count of lines
+    async fn converse(
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        script: &[(&str, usize)],
+    ) -> Vec<String> {
+        let mut stream = BufReader::new(stream);
+        let mut received = Vec::new();
+        for (line, replies) in script {
+            stream.write_all(line.as_bytes()).await.unwrap();
+            for _ in 0..*replies {
+                let mut reply = String::new();
+                stream.read_line(&mut reply).await.unwrap();
+                received.push(reply.trim_end().to_string());
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_smtp() {
+        let (mut server, client) = duplex(4096);
+        let client = tokio::spawn(async move {
+            converse(
+                client,
+                &[
+                    ("", 1),
+                    ("STARTTLS\r\n", 1),
+                    ("MAIL FROM:<a@example.com>\r\n", 1),
+                    ("EHLO client.example.com\r\n", 2),
+                    ("STARTTLS\r\n", 1),
+                ],
+            )
+            .await
+        });
+        let preamble = accept(&mut server, Protocol::Smtp, "mx.example.com", TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.await.unwrap(),
+            [
+                "220 mx.example.com ESMTP",
+                "503 5.5.1 Send EHLO first",
+                "530 5.7.0 Must issue a STARTTLS command first",
+                "250-mx.example.com",
+                "250 STARTTLS",
+                "220 2.0.0 Ready to start TLS",
+            ]
+        );
+        assert_eq!(preamble.replay, b"EHLO client.example.com\r\n");
+
+        let (mut backend, server) = duplex(4096);
+        let server = tokio::spawn(async move {
+            converse(
+                server,
+                &[
+                    ("220-backend ESMTP\r\n220 more\r\n", 1),
+                    ("250-backend\r\n250-PIPELINING\r\n250 STARTTLS\r\n", 1),
+                    ("220 go ahead\r\n", 0),
+                ],
+            )
+            .await
+        });
+        preamble.connect(&mut backend, false).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            ["EHLO client.example.com", "STARTTLS"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smtp_quit_and_terminate() {
+        let (mut server, client) = duplex(4096);
+        tokio::spawn(converse(client, &[("", 1), ("QUIT\r\n", 1)]));
+        assert_eq!(
+            accept(&mut server, Protocol::Smtp, "mx", TIMEOUT)
+                .await
+                .unwrap(),
+            None
+        );
+
+        let preamble = Preamble {
+            protocol: Protocol::Smtp,
+            replay: b"EHLO a\r\n".to_vec(),
+            timeout: TIMEOUT,
+        };
+        let (mut backend, mut server) = duplex(4096);
+        server.write_all(b"220 backend ESMTP\r\n").await.unwrap();
+        preamble.connect(&mut backend, true).await.unwrap();
+
+        let (mut backend, mut server) = duplex(4096);
+        server.write_all(b"554 go away\r\n").await.unwrap();
+        assert!(preamble.connect(&mut backend, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_imap() {
+        let (mut server, client) = duplex(4096);
+        let client = tokio::spawn(async move {
+            converse(
+                client,
+                &[
+                    ("", 1),
+                    ("a1 CAPABILITY\r\n", 2),
+                    ("a2 LOGIN user password\r\n", 1),
+                    ("a3 STARTTLS\r\n", 1),
+                ],
+            )
+            .await
+        });
+        let preamble = accept(&mut server, Protocol::Imap, "imap", TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.await.unwrap(),
+            [
+                "* OK [CAPABILITY IMAP4rev1 IMAP4rev2 STARTTLS LOGINDISABLED] imap ready",
+                "* CAPABILITY IMAP4rev1 IMAP4rev2 STARTTLS LOGINDISABLED",
+                "a1 OK CAPABILITY completed",
+                "a2 BAD Must issue a STARTTLS command first",
+                "a3 OK Begin TLS negotiation now",
+            ]
+        );
+
+        let (mut backend, server) = duplex(4096);
+        let server = tokio::spawn(async move {
+            converse(
+                server,
+                &[
+                    ("* OK backend\r\n", 1),
+                    ("* CAPABILITY IMAP4rev1\r\ntlslb OK go\r\n", 0),
+                ],
+            )
+            .await
+        });
+        preamble.connect(&mut backend, false).await.unwrap();
+        assert_eq!(server.await.unwrap(), ["tlslb STARTTLS"]);
+    }
+
+    #[tokio::test]
+    async fn test_pop3() {
+        let (mut server, client) = duplex(4096);
+        let client = tokio::spawn(async move {
+            converse(
+                client,
+                &[("", 1), ("CAPA\r\n", 3), ("USER a\r\n", 1), ("STLS\r\n", 1)],
+            )
+            .await
+        });
+        let preamble = accept(&mut server, Protocol::Pop3, "pop", TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.await.unwrap(),
+            [
+                "+OK pop ready",
+                "+OK Capability list follows",
+                "STLS",
+                ".",
+                "-ERR Must issue a STLS command first",
+                "+OK Begin TLS negotiation",
+            ]
+        );
+
+        let (mut backend, server) = duplex(4096);
+        let server = tokio::spawn(async move {
+            converse(server, &[("+OK backend\r\n", 1), ("+OK\r\n", 0)]).await
+        });
+        preamble.connect(&mut backend, false).await.unwrap();
+        assert_eq!(server.await.unwrap(), ["STLS"]);
+    }
+
+    #[tokio::test]
+    async fn test_xmpp() {
+        let header = "<?xml version='1.0'?><stream:stream to=\"example.com\" \
+                      xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' version='1.0'>";
+        let (mut server, mut client) = duplex(4096);
+        client.write_all(header.as_bytes()).await.unwrap();
+        client
+            .write_all(b"<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>")
+            .await
+            .unwrap();
+        let preamble = accept(&mut server, Protocol::Xmpp, "xmpp", TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("from='example.com'"), "{response}");
+        assert!(response.contains("xmlns='jabber:client'"), "{response}");
+        assert!(
+            response
+                .ends_with("</stream:features><proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>")
+        );
+        assert_eq!(preamble.replay, header.as_bytes());
+
+        let (mut backend, mut server) = duplex(4096);
+        server
+            .write_all(
+                b"<stream:stream id='1'><stream:features>\
+                  <starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/></stream:features>\
+                  <proceed xmlns='urn:ietf:params:xml:ns:xmpp-tls'></proceed>",
+            )
+            .await
+            .unwrap();
+        preamble.connect(&mut backend, false).await.unwrap();
+        drop(backend);
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(
+            sent,
+            format!("{header}<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_postgres() {
+        let (mut server, mut client) = duplex(4096);
+        client.write_all(&POSTGRES_GSSENC_REQUEST).await.unwrap();
+        client.write_all(&POSTGRES_SSL_REQUEST).await.unwrap();
+        let preamble = accept(&mut server, Protocol::Postgres, "pg", TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut answers = [0u8; 2];
+        client.read_exact(&mut answers).await.unwrap();
+        assert_eq!(&answers, b"NS");
+
+        let (mut backend, mut server) = duplex(4096);
+        server.write_all(b"S").await.unwrap();
+        preamble.connect(&mut backend, false).await.unwrap();
+        let mut request = [0u8; 8];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, POSTGRES_SSL_REQUEST);
+
+        let (mut backend, mut server) = duplex(4096);
+        server.write_all(b"N").await.unwrap();
+        assert!(preamble.connect(&mut backend, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_postgres_without_tls() {
+        // StartupMessage of protocol 3.0 with user postgres
+        let startup = b"\0\0\0\x17\0\x03\0\0user\0postgres\0\0";
+        let (mut server, mut client) = duplex(4096);
+        client.write_all(startup).await.unwrap();
+        assert_eq!(
+            accept(&mut server, Protocol::Postgres, "pg", TIMEOUT)
+                .await
+                .unwrap(),
+            None
+        );
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer[0], b'E');
+        assert_eq!(
+            answer.len(),
+            1 + u32::from_be_bytes(answer[1..5].try_into().unwrap()) as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_after_starttls() {
+        let (mut server, mut client) = duplex(4096);
+        client
+            .write_all(b"EHLO a\r\nSTARTTLS\r\n\x16\x03\x01")
+            .await
+            .unwrap();
+        assert!(
+            accept(&mut server, Protocol::Smtp, "mx", TIMEOUT)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_xml_attribute() {
+        let tag = "<stream:stream to='example.com' xmlns=\"jabber:server\">";
+        assert_eq!(xml_attribute(tag, "to"), Some("example.com"));
+        assert_eq!(xml_attribute(tag, "xmlns"), Some("jabber:server"));
+        assert_eq!(xml_attribute(tag, "from"), None);
+    }
+}
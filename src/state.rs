@@ -1,59 +1,309 @@
 use std::{
-    collections::{BTreeSet, HashMap, VecDeque},
-    net::SocketAddr,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{
-        Arc,
-        atomic::{AtomicU32, Ordering},
+        Arc, Weak,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow, bail};
 use futures::FutureExt;
 use ip_database::IpDatabase;
-use socket2::{SockRef, TcpKeepalive};
-use tokio::net::{TcpStream, lookup_host};
-use tracing::{debug, error, warn};
+use tokio::{
+    net::UnixStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, error, info, warn};
 
-use crate::config::{Backend, Config};
+use crate::{
+    access::AccessControl,
+    bandwidth::Shaper,
+    config::{
+        AddressFamily, Backend, BackendKind, Config, Frontend, FrontendKind, Ja4PolicyConfig,
+        UNIX_PREFIX, unix_path,
+    },
+    dns::DnsResolver,
+    dynamic::DynamicForwarder,
+    ech::EchKeys,
+    happy_eyeballs,
+    ja4_policy::Ja4Policy,
+    metrics::PoolStats,
+    preconnect::AdaptiveSizer,
+    rate_limit::RateLimits,
+    reload::Reload,
+    routing::Router,
+    socket::Connector,
+    stream::Stream,
+};
 
 pub struct State {
-    pub pools: HashMap<String, Pool>,
+    pub config: Arc<Config>,
+    pub pools: HashMap<String, Arc<Pool>>,
+    pub router: Router,
+    pub frontends: HashMap<String, FrontendState>,
     pub ip_to_asn_database: IpDatabase,
 }
 
+pub struct FrontendState {
+    pub access: Arc<AccessControl>,
+    pub ja4_policy: Option<Arc<Ja4Policy>>,
+    pub rate_limits: RateLimits,
+    /// Slots of client connections if `max-connections` is set
+    pub connection_slots: Option<Arc<Semaphore>>,
+    /// Keys to decrypt Encrypted Client Hello, if configured
+    pub ech: Option<EchKeys>,
+}
+
 impl State {
     pub async fn new(config: Arc<Config>) -> Result<Self> {
+        let router = Router::new(&config)?;
         let mut pools = HashMap::new();
 
+        let resolver = Arc::new(DnsResolver::new(&config.dns)?);
+        let self_addresses: Vec<_> = config
+            .frontends
+            .values()
+            .filter_map(|frontend| frontend.listen_address.tcp())
+            .map(|addr| addr.ip().to_canonical())
+            // dynamic backends refuse every address of the host for wildcard listeners
+            .filter(|ip| !ip.is_unspecified())
+            .collect();
+
         for (domain, backend) in &config.backends {
-            pools.insert(domain.clone(), Pool::new(Arc::clone(backend)).await?);
+            check_suspicious_backend(&config, backend.ja4_policy.as_ref())
+                .with_context(|| format!("invalid JA4 policy of backend {domain:?}"))?;
+            for alpn_route in &backend.alpn {
+                if !config.backends.contains_key(&alpn_route.backend) {
+                    bail!(
+                        "backend {domain:?} forwards ALPN {:?} to unknown backend {:?}",
+                        alpn_route.protocols,
+                        alpn_route.backend
+                    );
+                }
+            }
+            pools.insert(
+                domain.clone(),
+                Pool::new(Arc::clone(backend), &self_addresses, &resolver)
+                    .await
+                    .with_context(|| format!("failed setting up backend {domain:?}"))?,
+            );
         }
 
-        let mut ip_to_asn_database = IpDatabase::new();
-        ip_to_asn_database
-            .load_routing_table_txt(&include_bytes!("./table.txt")[..])
-            .unwrap();
+        let mut frontends = HashMap::new();
+        for (name, frontend) in &config.frontends {
+            check_suspicious_backend(&config, frontend.ja4_policy.as_ref())
+                .with_context(|| format!("invalid JA4 policy of frontend {name:?}"))?;
+            check_frontend_backend(&config, frontend)
+                .with_context(|| format!("invalid backend of frontend {name:?}"))?;
+            if frontend.max_connections == Some(0) {
+                bail!("max-connections of frontend {name:?} must be positive");
+            }
+            let access = AccessControl::new(frontend.access.clone())
+                .with_context(|| format!("invalid access list of frontend {name:?}"))?;
+            let ja4_policy = frontend
+                .ja4_policy
+                .clone()
+                .map(|policy| Ja4Policy::new(policy).map(Arc::new))
+                .transpose()
+                .with_context(|| format!("invalid JA4 policy of frontend {name:?}"))?;
+            let rate_limits = RateLimits::new(&frontend.rate_limits)
+                .with_context(|| format!("invalid rate limits of frontend {name:?}"))?;
+            let ech = (!frontend.ech.is_empty())
+                .then(|| EchKeys::load(&frontend.ech))
+                .transpose()
+                .with_context(|| format!("invalid ECH keys of frontend {name:?}"))?;
+            frontends.insert(
+                name.clone(),
+                FrontendState {
+                    access: Arc::new(access),
+                    ja4_policy,
+                    rate_limits,
+                    connection_slots: frontend
+                        .max_connections
+                        .map(|max| Arc::new(Semaphore::new(max))),
+                    ech,
+                },
+            );
+        }
+
+        let mut reloadables: Vec<Arc<dyn Reload>> = Vec::new();
+        for frontend in frontends.values() {
+            reloadables.push(frontend.access.clone());
+            if let Some(policy) = &frontend.ja4_policy {
+                reloadables.push(policy.clone());
+            }
+        }
+        for pool in pools.values() {
+            reloadables.push(pool.access.clone());
+            if let Some(policy) = &pool.ja4_policy {
+                reloadables.push(policy.clone());
+            }
+        }
+        tokio::spawn(Self::reload_periodically(
+            reloadables,
+            pools.values().cloned().collect(),
+            resolver,
+            Duration::from_secs(config.reload_interval_secs.max(1)),
+        ));
 
         Ok(Self {
+            config,
             pools,
-            ip_to_asn_database,
+            router,
+            frontends,
+            ip_to_asn_database: load_ip_to_asn_database(),
         })
     }
+
+    /// Reloads changed files and resolves the backend addresses again
+    ///
+    /// Lookups are answered by the cache of the resolver until the TTL of the records expired.
+    async fn reload_periodically(
+        reloadables: Vec<Arc<dyn Reload>>,
+        pools: Vec<Arc<Pool>>,
+        resolver: Arc<DnsResolver>,
+        interval: Duration,
+    ) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            for reloadable in &reloadables {
+                reloadable.reload_if_changed();
+            }
+            for pool in &pools {
+                pool.resolve(&resolver).await;
+            }
+        }
+    }
+
+    /// Selects the pool for a client by SNI and the offered ALPN protocols
+    pub fn select_pool(&self, sni: &str, alpn: &[&[u8]]) -> Option<&Arc<Pool>> {
+        let backends = &self.config.backends;
+        self.pools
+            .get(self.router.backend_by_sni(backends, sni, alpn)?)
+    }
+}
+
+fn check_suspicious_backend(config: &Config, policy: Option<&Ja4PolicyConfig>) -> Result<()> {
+    if let Some(backend) = policy.and_then(|policy| policy.suspicious_backend.as_ref())
+        && !config.backends.contains_key(backend)
+    {
+        bail!("suspicious backend {backend:?} does not exist");
+    }
+    Ok(())
+}
+
+fn check_frontend_backend(config: &Config, frontend: &Frontend) -> Result<()> {
+    if frontend.kind == FrontendKind::Tcp && frontend.backend.is_none() {
+        bail!("TCP frontends need a backend");
+    }
+    for backend in [&frontend.backend, &frontend.http.acme_backend]
+        .into_iter()
+        .flatten()
+        .chain(frontend.no_sni.backends())
+    {
+        if !config.backends.contains_key(backend) {
+            bail!("backend {backend:?} does not exist");
+        }
+    }
+    Ok(())
 }
 
+pub fn load_ip_to_asn_database() -> IpDatabase {
+    let mut ip_to_asn_database = IpDatabase::new();
+    ip_to_asn_database
+        .load_routing_table_txt(&include_bytes!("./table.txt")[..])
+        .unwrap();
+    ip_to_asn_database
+}
+
+/// A backend host with all addresses it resolved to
 pub struct BackendState {
-    pub addr: SocketAddr,
+    /// The configured address or the target of the SRV record
+    pub host: String,
+    pub addrs: Vec<SocketAddr>,
+    /// Path of the Unix socket, backends with a path have no addresses
+    pub unix_path: Option<PathBuf>,
+    /// Address of the last successful connection, tried first next time
+    pub last_connected: parking_lot::Mutex<Option<SocketAddr>>,
+    /// SRV priority, backends with a higher priority are used if the lower ones fail
+    pub priority: u16,
+    /// SRV weight, backends get connections proportional to their weight
+    pub weight: u16,
     pub open_connections: AtomicU32,
 }
 
 impl BackendState {
-    pub fn new(addr: SocketAddr) -> Self {
+    pub fn new(host: String, addrs: Vec<SocketAddr>, priority: u16, weight: u16) -> Self {
         Self {
-            addr,
+            host,
+            addrs,
+            unix_path: None,
+            last_connected: parking_lot::Mutex::new(None),
+            priority,
+            weight,
             open_connections: AtomicU32::new(0),
         }
     }
+
+    /// Whether both connect to the same addresses with the same priority and weight
+    fn same_target(&self, other: &Self) -> bool {
+        self.host == other.host
+            && self.addrs == other.addrs
+            && self.unix_path == other.unix_path
+            && self.priority == other.priority
+            && self.weight == other.weight
+    }
+
+    /// A backend listening on a Unix socket
+    pub fn unix(path: &Path) -> Self {
+        Self {
+            unix_path: Some(path.to_path_buf()),
+            ..Self::new(format!("{UNIX_PREFIX}{}", path.display()), Vec::new(), 0, 1)
+        }
+    }
+
+    /// Races connections to the addresses of the host (Happy Eyeballs)
+    ///
+    /// The address that connected last time is tried first,
+    /// the others alternate between the address families.
+    pub async fn connect(
+        &self,
+        connector: &Connector,
+        address_family: AddressFamily,
+        attempt_delay: Duration,
+    ) -> Result<Stream> {
+        if let Some(path) = &self.unix_path {
+            let connection = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("failed connecting to {}", self.host))?;
+            return Ok(Stream::Unix(connection));
+        }
+        let last_connected = *self.last_connected.lock();
+        let mut addrs: Vec<_> = self
+            .addrs
+            .iter()
+            .copied()
+            .filter(|addr| address_family.allows(addr.ip()))
+            .collect();
+        addrs = happy_eyeballs::interleave(&addrs, address_family.prefers_ipv6());
+        if let Some(position) = addrs.iter().position(|addr| Some(*addr) == last_connected) {
+            addrs[..=position].rotate_right(1);
+        }
+
+        let (connection, addr) =
+            happy_eyeballs::connect(&addrs, attempt_delay, |addr| connector.connect(addr))
+                .await
+                .with_context(|| format!("failed connecting to {}", self.host))?;
+        if last_connected != Some(addr) {
+            debug!(host = self.host, %addr, "connected to new address");
+            *self.last_connected.lock() = Some(addr);
+        }
+        Ok(Stream::Tcp(connection))
+    }
 }
 
 pub struct ConnectionRef {
@@ -66,8 +316,11 @@ impl ConnectionRef {
             .open_connections
             .fetch_add(1, Ordering::Relaxed)
             .saturating_add(1);
-        let backend_socket_addr = backend_state.addr;
-        debug!(connections, %backend_socket_addr, "opened connection");
+        debug!(
+            connections,
+            backend = backend_state.host,
+            "opened connection"
+        );
         Self { backend_state }
     }
 }
@@ -79,97 +332,643 @@ impl Drop for ConnectionRef {
             .open_connections
             .fetch_sub(1, Ordering::Relaxed)
             .saturating_sub(1);
-        let backend_socket_addr = self.backend_state.addr;
-        debug!(connections, %backend_socket_addr, "closed connection");
+        debug!(
+            connections,
+            backend = self.backend_state.host,
+            "closed connection"
+        );
     }
 }
 
 pub struct Pool {
-    pub backends: Vec<Arc<BackendState>>,
-    pub slots: Arc<parking_lot::Mutex<VecDeque<(TcpStream, ConnectionRef)>>>,
+    /// Backends of the last successful resolution of the configured addresses
+    backends: parking_lot::RwLock<Vec<Arc<BackendState>>>,
+    pub slots: Arc<parking_lot::Mutex<VecDeque<(Stream, ConnectionRef)>>>,
+    /// Count of pre-connections that are currently being established
+    pub pending: Arc<AtomicUsize>,
+    pub sizer: Option<Arc<AdaptiveSizer>>,
+    pub stats: PoolStats,
+    pub access: Arc<AccessControl>,
+    pub ja4_policy: Option<Arc<Ja4Policy>>,
+    pub rate_limits: RateLimits,
+    pub queue: Option<ConnectionQueue>,
+    pub shaper: Option<Shaper>,
+    /// Set for dynamic backends, which have no fixed addresses
+    pub dynamic: Option<DynamicForwarder>,
+    pub connector: Arc<Connector>,
     pub config: Arc<Backend>,
 }
 
+/// Bounded FIFO queue of clients waiting for one of `max-connections` slots
+pub struct ConnectionQueue {
+    slots: Arc<Semaphore>,
+    max_connections: usize,
+    /// Count of clients currently waiting
+    pub waiting: AtomicUsize,
+    max_waiting: usize,
+    timeout: Duration,
+}
+
+impl ConnectionQueue {
+    pub fn new(max_connections: usize, max_waiting: usize, timeout: Duration) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            max_connections,
+            waiting: AtomicUsize::new(0),
+            max_waiting,
+            timeout,
+        }
+    }
+
+    /// Waits for a free slot, fails if the queue is full or the timeout passed
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = Arc::clone(&self.slots).try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.max_waiting {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            bail!("backend is at max connections and the wait queue is full");
+        }
+        // also decrements when the waiting client is cancelled
+        let _waiting = WaitingGuard(&self.waiting);
+
+        // the semaphore hands out permits in FIFO order
+        tokio::time::timeout(self.timeout, Arc::clone(&self.slots).acquire_owned())
+            .await
+            .context("timed out waiting for a free backend connection slot")?
+            .context("connection slots were closed")
+    }
+
+    /// Count of client connections currently holding a slot
+    pub fn active(&self) -> usize {
+        self.max_connections
+            .saturating_sub(self.slots.available_permits())
+    }
+}
+
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Resolves the addresses of a backend
+///
+/// Addresses are grouped by host, so the address families of a host can be raced.
+async fn resolve_backends(
+    config: &Backend,
+    resolver: &DnsResolver,
+) -> Result<Vec<Arc<BackendState>>> {
+    let mut hosts: BTreeMap<String, (BTreeSet<SocketAddr>, u16, u16)> = BTreeMap::new();
+    let mut unix_backends = Vec::new();
+    for address in &config.addresses {
+        if let Some(path) = unix_path(address) {
+            unix_backends.push(Arc::new(BackendState::unix(path)));
+            continue;
+        }
+        for resolved in resolver.resolve_backend(address).await? {
+            hosts
+                .entry(resolved.host)
+                .or_insert_with(|| (BTreeSet::new(), resolved.priority, resolved.weight))
+                .0
+                .insert(resolved.addr);
+        }
+    }
+
+    Ok(hosts
+        .into_iter()
+        .map(|(host, (addrs, priority, weight))| {
+            Arc::new(BackendState::new(
+                host,
+                addrs.into_iter().collect(),
+                priority,
+                weight,
+            ))
+        })
+        .chain(unix_backends)
+        .collect())
+}
+
 impl Pool {
-    pub async fn new(config: Arc<Backend>) -> Result<Self> {
-        let mut addresses = BTreeSet::new();
-        for host in &config.addresses {
-            addresses.extend(lookup_host(host).await?);
+    pub async fn new(
+        config: Arc<Backend>,
+        self_addresses: &[IpAddr],
+        resolver: &Arc<DnsResolver>,
+    ) -> Result<Arc<Self>> {
+        if config.transparent
+            && (config.preconnect_count.unwrap_or(0) > 0 || config.adaptive_preconnect.is_some())
+        {
+            bail!("transparent backends can not preconnect, the client address is not known yet");
+        }
+        if config.kind == BackendKind::Dynamic
+            && (config.preconnect_count.unwrap_or(0) > 0 || config.adaptive_preconnect.is_some())
+        {
+            bail!("dynamic backends can not preconnect, the SNI of the client is not known yet");
+        }
+        if config.max_connections == Some(0) {
+            bail!("max-connections must be positive");
         }
 
-        let backends = addresses
-            .iter()
-            .map(|addr| Arc::new(BackendState::new(*addr)))
-            .collect();
+        if (config.transparent || config.kind == BackendKind::Dynamic)
+            && config
+                .addresses
+                .iter()
+                .any(|address| unix_path(address).is_some())
+        {
+            bail!("Unix sockets can not be used by transparent or dynamic backends");
+        }
 
-        let preconnect_count = config.preconnect_count.unwrap_or(0);
+        let backends = resolve_backends(&config, resolver).await?;
+        if config.kind == BackendKind::Static && backends.is_empty() {
+            bail!("static backends need at least one address");
+        }
 
-        let pool = Self {
-            backends,
+        let sizer = config
+            .adaptive_preconnect
+            .clone()
+            .map(|adaptive| Arc::new(AdaptiveSizer::new(adaptive)));
+
+        let pool = Arc::new(Self {
+            backends: parking_lot::RwLock::new(backends),
             slots: Arc::new(Default::default()),
+            pending: Arc::new(AtomicUsize::new(0)),
+            sizer,
+            stats: PoolStats::default(),
+            access: Arc::new(AccessControl::new(config.access.clone())?),
+            ja4_policy: config
+                .ja4_policy
+                .clone()
+                .map(|policy| Ja4Policy::new(policy).map(Arc::new))
+                .transpose()?,
+            rate_limits: RateLimits::new(&config.rate_limits)?,
+            queue: config.max_connections.map(|max_connections| {
+                ConnectionQueue::new(
+                    max_connections,
+                    config.max_queued,
+                    Duration::from_millis(config.queue_timeout_ms),
+                )
+            }),
+            shaper: config.bandwidth.clone().map(Shaper::new).transpose()?,
+            dynamic: match config.kind {
+                BackendKind::Static => None,
+                BackendKind::Dynamic => Some(DynamicForwarder::new(
+                    &config,
+                    self_addresses.to_vec(),
+                    Arc::clone(resolver),
+                )?),
+            },
+            connector: Arc::new(Connector::new(&config)?),
             config,
-        };
+        });
+
+        pool.fill();
 
-        for _ in 0..preconnect_count {
-            pool.request_connection();
+        if let Some(sizer) = &pool.sizer {
+            tokio::spawn(Self::adjust_periodically(
+                Arc::downgrade(&pool),
+                sizer.adjust_interval(),
+            ));
         }
 
         Ok(pool)
     }
 
-    pub fn request_connection(&self) {
-        let connections = Arc::clone(&self.slots);
-        let backend = self
+    /// Resolves the configured addresses again
+    ///
+    /// Backends whose addresses did not change are kept with their connection counts.
+    /// If the lookup fails or finds nothing, the previous backends are kept.
+    pub async fn resolve(&self, resolver: &DnsResolver) {
+        if self.dynamic.is_some() {
+            return;
+        }
+        let resolved = match resolve_backends(&self.config, resolver).await {
+            Ok(resolved) if !resolved.is_empty() => resolved,
+            Ok(_) => {
+                warn!("backend addresses resolved to nothing, keeping the previous ones");
+                return;
+            }
+            Err(err) => {
+                warn!(
+                    ?err,
+                    "failed resolving backend addresses, keeping the previous ones"
+                );
+                return;
+            }
+        };
+        self.replace_backends(resolved);
+    }
+
+    /// Replaces the backends by resolved ones, keeping the unchanged ones
+    ///
+    /// Returns whether any backend changed.
+    fn replace_backends(&self, resolved: Vec<Arc<BackendState>>) -> bool {
+        let mut backends = self.backends.write();
+        let resolved: Vec<_> = resolved
+            .into_iter()
+            .map(|new| {
+                backends
+                    .iter()
+                    .find(|old| old.same_target(&new))
+                    .map_or(new, Arc::clone)
+            })
+            .collect();
+        let changed = resolved.len() != backends.len()
+            || resolved
+                .iter()
+                .zip(backends.iter())
+                .any(|(new, old)| !Arc::ptr_eq(new, old));
+        if changed {
+            info!(
+                hosts = ?resolved.iter().map(|backend| &backend.host).collect::<Vec<_>>(),
+                "backend addresses changed"
+            );
+            *backends = resolved;
+        }
+        changed
+    }
+
+    /// Count of connections that should be held idle
+    pub fn idle_target(&self) -> usize {
+        self.sizer.as_ref().map_or_else(
+            || self.config.preconnect_count.unwrap_or(0),
+            |sizer| sizer.target(),
+        )
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.slots.lock().len()
+    }
+
+    /// Requests new connections until the idle target is reached
+    pub fn fill(&self) {
+        let target = self.idle_target();
+        let available = self.idle_count() + self.pending.load(Ordering::Relaxed);
+        for _ in available..target {
+            self.request_connection();
+        }
+    }
+
+    async fn adjust_periodically(pool: Weak<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            let Some(sizer) = &pool.sizer else {
+                return;
+            };
+            let target = sizer.update();
+
+            // close the oldest connections first, they are the most likely to be stale
+            let excess: Vec<_> = {
+                let mut slots = pool.slots.lock();
+                let excess = slots.len().saturating_sub(target);
+                slots.drain(..excess).collect()
+            };
+            if !excess.is_empty() {
+                debug!(closed = excess.len(), target, "shrinking pool");
+            }
+
+            pool.fill();
+        }
+    }
+
+    /// Backends in the order connections are tried: by priority,
+    /// then by the fewest connections relative to their weight
+    fn candidates(&self) -> Vec<Arc<BackendState>> {
+        // the connection counts change concurrently, sorting needs a snapshot
+        let mut backends: Vec<_> = self
             .backends
+            .read()
             .iter()
-            .min_by_key(|state| state.open_connections.load(Ordering::Relaxed))
-            .expect("pool has at least one backend");
-        let sock_addr = backend.addr;
-        let connection_ref = ConnectionRef::new(Arc::clone(backend));
+            .map(|state| {
+                let connections = u64::from(state.open_connections.load(Ordering::Relaxed)) + 1;
+                (connections, Arc::clone(state))
+            })
+            .collect();
+        backends.sort_by(|(a_connections, a), (b_connections, b)| {
+            a.priority.cmp(&b.priority).then_with(|| {
+                (a_connections * u64::from(b.weight.max(1)))
+                    .cmp(&(b_connections * u64::from(a.weight.max(1))))
+            })
+        });
+        backends.into_iter().map(|(_, state)| state).collect()
+    }
 
+    /// Selects the backend with the fewest connections relative to its weight
+    /// among the backends with the lowest priority
+    fn select_backend(&self) -> Option<Arc<BackendState>> {
+        self.candidates().into_iter().next()
+    }
+
+    pub fn request_connection(&self) {
+        let connections = Arc::clone(&self.slots);
+        let pending = Arc::clone(&self.pending);
+        let sizer = self.sizer.clone();
+        let candidates = self.candidates();
+        let connector = Arc::clone(&self.connector);
+        let address_family = self.config.address_family;
+        let attempt_delay = Duration::from_millis(self.config.happy_eyeballs_delay_ms);
+
+        pending.fetch_add(1, Ordering::Relaxed);
         tokio::spawn(async move {
-            match TcpStream::connect(sock_addr).await {
+            let connect_start = Instant::now();
+            match connect_any(candidates, &connector, address_family, attempt_delay).await {
                 Ok(connection) => {
-                    connection.set_nodelay(true).unwrap();
-                    let sf = SockRef::from(&connection);
-                    sf.set_keepalive(true).unwrap();
-                    let ka = TcpKeepalive::new().with_time(Duration::from_secs(30));
-                    sf.set_tcp_keepalive(&ka).unwrap();
-                    connections.lock().push_back((connection, connection_ref));
+                    if let Some(sizer) = sizer {
+                        sizer.record_connect_latency(connect_start.elapsed());
+                    }
+                    connections.lock().push_back(connection);
                 }
                 Err(err) => {
-                    error!(?err, ?sock_addr, "failed to request connection");
+                    error!(?err, "failed to request connection");
                 }
             }
+            pending.fetch_sub(1, Ordering::Relaxed);
         });
     }
 
-    pub async fn get_connection(&self) -> Result<(TcpStream, ConnectionRef)> {
-        while let Some((conn, connection_ref)) = self.slots.lock().pop_front() {
-            self.request_connection();
+    /// Connects to the backend for a client
+    ///
+    /// Dynamic backends connect to the SNI of the client, others use [`Self::get_connection`].
+    /// Transparent backends connect from `peer_addr` and never use pre-opened connections.
+    /// Both addresses are `None` for clients of Unix sockets.
+    pub async fn connect(
+        &self,
+        sni: Option<&str>,
+        local_addr: Option<SocketAddr>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<(Stream, ConnectionRef)> {
+        let transparent;
+        let connector = if self.config.transparent {
+            let peer_addr =
+                peer_addr.context("transparent backends need the IP address of the client")?;
+            transparent = self.connector.for_client(peer_addr.ip());
+            &transparent
+        } else {
+            &*self.connector
+        };
+        let Some(dynamic) = &self.dynamic else {
+            if self.config.transparent {
+                return self.open_connection(connector).await;
+            }
+            return self.get_connection().await;
+        };
+        let sni = sni.context("dynamic backends need the SNI of the client")?;
+        let local_addr =
+            local_addr.context("dynamic backends need the local address of the client")?;
+        let (connection, addr) = dynamic.connect(sni, local_addr, connector).await?;
+        let backend = BackendState::new(sni.to_string(), vec![addr], 0, 1);
+        *backend.last_connected.lock() = Some(addr);
+        Ok((
+            Stream::Tcp(connection),
+            ConnectionRef::new(Arc::new(backend)),
+        ))
+    }
+
+    /// Selects the address of a backend for a UDP flow
+    ///
+    /// UDP has no connections to race, the address that last connected via TCP is preferred.
+    pub fn datagram_target(&self) -> Option<(SocketAddr, ConnectionRef)> {
+        if self.dynamic.is_some() {
+            return None;
+        }
+        let backend = self.select_backend()?;
+        let last_connected = *backend.last_connected.lock();
+        let addr = last_connected.or_else(|| {
+            let family = self.config.address_family;
+            let addrs: Vec<_> = backend
+                .addrs
+                .iter()
+                .copied()
+                .filter(|addr| family.allows(addr.ip()))
+                .collect();
+            happy_eyeballs::interleave(&addrs, family.prefers_ipv6())
+                .first()
+                .copied()
+        })?;
+        Some((addr, ConnectionRef::new(backend)))
+    }
+
+    pub async fn get_connection(&self) -> Result<(Stream, ConnectionRef)> {
+        if let Some(sizer) = &self.sizer {
+            sizer.record_arrival();
+        }
+
+        loop {
+            let Some((conn, connection_ref)) = self.slots.lock().pop_front() else {
+                break;
+            };
+            self.fill();
 
             // that's how you check if a socket is closed
             let mut buf = [0u8; 16];
             if let Some(Ok(0)) = conn.peek(&mut buf).now_or_never() {
                 warn!("connection was closed by remote - try next connection");
             } else {
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
                 return Ok((conn, connection_ref));
             }
         }
 
         // fallback if pool is empty
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        self.fill();
 
-        let backend = self
-            .backends
-            .iter()
-            .min_by_key(|state| state.open_connections.load(Ordering::Relaxed))
-            .expect("pool has at least one backend");
-        let sock_addr = backend.addr;
-        let connection_ref = ConnectionRef::new(Arc::clone(backend));
-        let connection = TcpStream::connect(sock_addr)
+        self.open_connection(&self.connector)
+            .await
+            .context("pool is empty and failed to open connection as fallback")
+    }
+
+    /// Opens a new connection to the backend selected by priority and load
+    async fn open_connection(&self, connector: &Connector) -> Result<(Stream, ConnectionRef)> {
+        let connect_start = Instant::now();
+        let connection = connect_any(
+            self.candidates(),
+            connector,
+            self.config.address_family,
+            Duration::from_millis(self.config.happy_eyeballs_delay_ms),
+        )
+        .await?;
+        if let Some(sizer) = &self.sizer {
+            sizer.record_connect_latency(connect_start.elapsed());
+        }
+        Ok(connection)
+    }
+}
+
+/// Connects to the first of the backends that accepts the connection
+///
+/// Backends of a higher priority are only tried if all of the lower priority failed.
+async fn connect_any(
+    candidates: Vec<Arc<BackendState>>,
+    connector: &Connector,
+    address_family: AddressFamily,
+    attempt_delay: Duration,
+) -> Result<(Stream, ConnectionRef)> {
+    let mut last_error = None;
+    for backend in candidates {
+        let connection_ref = ConnectionRef::new(Arc::clone(&backend));
+        match backend
+            .connect(connector, address_family, attempt_delay)
+            .await
+        {
+            Ok(connection) => return Ok((connection, connection_ref)),
+            Err(err) => {
+                warn!(?err, backend = backend.host, "trying the next backend");
+                last_error = Some(err);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("pool has no backend")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connection_queue() {
+        let queue = Arc::new(ConnectionQueue::new(1, 1, Duration::from_millis(50)));
+
+        let first = queue.acquire().await.unwrap();
+        assert_eq!(queue.active(), 1);
+
+        let waiting = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.acquire().await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(queue.waiting.load(Ordering::Relaxed), 1);
+
+        // the queue is full
+        assert!(queue.acquire().await.is_err());
+
+        drop(first);
+        let second = waiting.await.unwrap().unwrap();
+        assert_eq!(queue.waiting.load(Ordering::Relaxed), 0);
+
+        // nobody releases the slot
+        assert!(queue.acquire().await.is_err());
+        assert_eq!(queue.waiting.load(Ordering::Relaxed), 0);
+        drop(second);
+        assert_eq!(queue.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pools() {
+        let resolver = Arc::new(DnsResolver::new(&toml::from_str("").unwrap()).unwrap());
+        for (backend, error) in [
+            (
+                "type = \"dynamic\"\ndomains = [\"*.example.com\"]\npreconnect-count = 1",
+                "dynamic backends can not preconnect",
+            ),
+            (
+                "type = \"dynamic\"\ndomains = [\"*.example.com\"]\nadaptive-preconnect = { max = 4 }",
+                "dynamic backends can not preconnect",
+            ),
+            (
+                "addresses = []",
+                "static backends need at least one address",
+            ),
+            (
+                "addresses = [\"192.0.2.1:443\"]\nmax-connections = 0",
+                "max-connections must be positive",
+            ),
+        ] {
+            let config: Backend = toml::from_str(backend).unwrap();
+            let Err(err) = Pool::new(Arc::new(config), &[], &resolver).await else {
+                panic!("{backend}");
+            };
+            assert!(err.to_string().starts_with(error), "{backend}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_again() {
+        let resolver = Arc::new(DnsResolver::new(&toml::from_str("").unwrap()).unwrap());
+        let config: Backend =
+            toml::from_str(r#"addresses = ["192.0.2.1:443", "192.0.2.2:443"]"#).unwrap();
+        let pool = Pool::new(Arc::new(config), &[], &resolver).await.unwrap();
+        let before = pool.backends.read().clone();
+
+        // literal addresses never change
+        pool.resolve(&resolver).await;
+        assert!(Arc::ptr_eq(&pool.backends.read()[0], &before[0]));
+
+        let changed = Arc::new(BackendState::new(
+            "192.0.2.2:443".to_string(),
+            vec!["192.0.2.3:443".parse().unwrap()],
+            0,
+            1,
+        ));
+        let unchanged = Arc::new(BackendState::new(
+            "192.0.2.1:443".to_string(),
+            vec!["192.0.2.1:443".parse().unwrap()],
+            0,
+            1,
+        ));
+        assert!(pool.replace_backends(vec![unchanged, Arc::clone(&changed)]));
+        let after = pool.backends.read().clone();
+        assert!(Arc::ptr_eq(&after[0], &before[0]));
+        assert!(Arc::ptr_eq(&after[1], &changed));
+        assert!(!pool.replace_backends(after.clone()));
+    }
+
+    #[tokio::test]
+    async fn test_priority_fallback() {
+        let resolver = Arc::new(DnsResolver::new(&toml::from_str("").unwrap()).unwrap());
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening_addr = listener.local_addr().unwrap();
+
+        let config: Backend = toml::from_str(&format!("addresses = [\"{closed_addr}\"]")).unwrap();
+        let pool = Pool::new(Arc::new(config), &[], &resolver).await.unwrap();
+        pool.replace_backends(vec![
+            Arc::new(BackendState::new(
+                "fallback".to_string(),
+                vec![listening_addr],
+                20,
+                1,
+            )),
+            Arc::new(BackendState::new(
+                "primary".to_string(),
+                vec![closed_addr],
+                10,
+                1,
+            )),
+        ]);
+        assert_eq!(pool.select_backend().unwrap().host, "primary");
+
+        let (_stream, connection_ref) = pool.get_connection().await.unwrap();
+        assert_eq!(connection_ref.backend_state.host, "fallback");
+        listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unix_backend() {
+        let path = std::env::temp_dir().join(format!("tlslb-backend-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let backend = BackendState::unix(&path);
+        assert_eq!(backend.host, format!("unix:{}", path.display()));
+        let stream = backend
+            .connect(
+                &Connector::default(),
+                AddressFamily::default(),
+                Duration::ZERO,
+            )
             .await
-            .context("pool is empty and failed to open connection as fallback")?;
-        connection.set_nodelay(true)?;
-        Ok((connection, connection_ref))
+            .unwrap();
+        assert!(matches!(stream, Stream::Unix(_)));
+        listener.accept().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
     }
 }
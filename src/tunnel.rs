@@ -0,0 +1,315 @@
+use std::str;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::http;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTHENTICATION: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const SOCKS_COMMAND_CONNECT: u8 = 1;
+const SOCKS_ADDRESS_DOMAIN: u8 = 3;
+const SOCKS_SUCCEEDED: u8 = 0;
+const SOCKS_NOT_ALLOWED: u8 = 2;
+const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+const CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const CONNECT_FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+const CONNECT_METHOD_NOT_ALLOWED: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\nallow: CONNECT\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+/// Protocols of explicit proxies
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Protocol {
+    /// HTTP/1.x `CONNECT` requests (RFC 9110, section 9.3.6)
+    HttpConnect,
+    /// SOCKS5 `CONNECT` with a domain name (RFC 1928), without authentication
+    Socks5,
+}
+
+/// Destination a client asked for
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    protocol: Protocol,
+    /// Lowercase domain name without trailing dot
+    pub host: String,
+    pub port: u16,
+    /// Data the client sent after the request
+    pub buffer: Vec<u8>,
+}
+
+/// Reads the request of a client
+///
+/// Returns `None` if the request is invalid or not supported, the client was answered already.
+pub async fn read_request(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    protocol: Protocol,
+    max_head_size: usize,
+) -> Result<Option<Request>> {
+    match protocol {
+        Protocol::HttpConnect => read_connect(stream, max_head_size).await,
+        Protocol::Socks5 => read_socks5(stream).await,
+    }
+}
+
+async fn read_connect(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    max_head_size: usize,
+) -> Result<Option<Request>> {
+    let mut buffer = match http::read_head(stream, max_head_size).await? {
+        Ok(buffer) => buffer,
+        Err(err) => {
+            stream.write_all(err.response()).await?;
+            return Ok(None);
+        }
+    };
+    let Ok(head) = http::parse_head(&buffer) else {
+        stream
+            .write_all(http::HeadError::Malformed.response())
+            .await?;
+        return Ok(None);
+    };
+    if head.method != "CONNECT" {
+        stream.write_all(CONNECT_METHOD_NOT_ALLOWED).await?;
+        return Ok(None);
+    }
+    // the target of CONNECT is host and port only
+    let Some((host, port)) = head
+        .path
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .filter(|(host, _port)| !host.is_empty())
+    else {
+        stream
+            .write_all(http::HeadError::Malformed.response())
+            .await?;
+        return Ok(None);
+    };
+    let host = normalize_host(host);
+    let end = http::find_head_end(&buffer).context("incomplete request head")?;
+    buffer.drain(..end);
+    Ok(Some(Request {
+        protocol: Protocol::HttpConnect,
+        host,
+        port,
+        buffer,
+    }))
+}
+
+async fn read_socks5(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<Option<Request>> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != SOCKS_VERSION {
+        return Ok(None);
+    }
+    let mut methods = vec![0u8; usize::from(greeting[1])];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS_NO_AUTHENTICATION) {
+        stream
+            .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS])
+            .await?;
+        return Ok(None);
+    }
+    stream
+        .write_all(&[SOCKS_VERSION, SOCKS_NO_AUTHENTICATION])
+        .await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _reserved, address_type] = request;
+    if version != SOCKS_VERSION {
+        return Ok(None);
+    }
+    if command != SOCKS_COMMAND_CONNECT {
+        stream
+            .write_all(&socks_reply(SOCKS_COMMAND_NOT_SUPPORTED))
+            .await?;
+        return Ok(None);
+    }
+    // routing needs a name, addresses are not accepted
+    if address_type != SOCKS_ADDRESS_DOMAIN {
+        stream
+            .write_all(&socks_reply(SOCKS_ADDRESS_NOT_SUPPORTED))
+            .await?;
+        return Ok(None);
+    }
+    let len = stream.read_u8().await?;
+    let mut host = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut host).await?;
+    let port = stream.read_u16().await?;
+    let Ok(host) = str::from_utf8(&host) else {
+        stream
+            .write_all(&socks_reply(SOCKS_ADDRESS_NOT_SUPPORTED))
+            .await?;
+        return Ok(None);
+    };
+    Ok(Some(Request {
+        protocol: Protocol::Socks5,
+        host: normalize_host(host),
+        port,
+        buffer: Vec::new(),
+    }))
+}
+
+/// Reply to a SOCKS5 request, with an unspecified bound address
+const fn socks_reply(reply: u8) -> [u8; 10] {
+    [SOCKS_VERSION, reply, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Request {
+    /// Tells the client that the tunnel is established
+    pub async fn accept(&self, stream: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        match self.protocol {
+            Protocol::HttpConnect => stream.write_all(CONNECT_ESTABLISHED).await?,
+            Protocol::Socks5 => stream.write_all(&socks_reply(SOCKS_SUCCEEDED)).await?,
+        }
+        Ok(())
+    }
+
+    /// Tells the client that the destination is not allowed and closes the connection
+    pub async fn deny(&self, mut stream: impl AsyncWrite + Unpin) -> Result<()> {
+        match self.protocol {
+            Protocol::HttpConnect => stream.write_all(CONNECT_FORBIDDEN).await?,
+            Protocol::Socks5 => stream.write_all(&socks_reply(SOCKS_NOT_ALLOWED)).await?,
+        }
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Whether the SNI of the client hello in the tunnel is the requested host
+    pub fn matches_sni(&self, sni: Option<&str>) -> bool {
+        sni.is_some_and(|sni| normalize_host(sni) == self.host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect() {
+        let (mut server, mut client) = duplex(4096);
+        client
+            .write_all(
+                b"CONNECT Example.com.:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03\x01",
+            )
+            .await
+            .unwrap();
+        let request = read_request(&mut server, Protocol::HttpConnect, 1024)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            request,
+            Request {
+                protocol: Protocol::HttpConnect,
+                host: "example.com".to_string(),
+                port: 443,
+                buffer: b"\x16\x03\x01".to_vec(),
+            }
+        );
+        assert!(request.matches_sni(Some("EXAMPLE.com")));
+        assert!(!request.matches_sni(Some("other.example.com")));
+        assert!(!request.matches_sni(None));
+
+        request.accept(&mut server).await.unwrap();
+        let mut response = vec![0u8; CONNECT_ESTABLISHED.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, CONNECT_ESTABLISHED);
+    }
+
+    #[tokio::test]
+    async fn test_connect_invalid() {
+        for (request, response) in [
+            (
+                b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".as_slice(),
+                CONNECT_METHOD_NOT_ALLOWED,
+            ),
+            (
+                b"CONNECT example.com HTTP/1.1\r\n\r\n",
+                http::HeadError::Malformed.response(),
+            ),
+        ] {
+            let (mut server, mut client) = duplex(4096);
+            client.write_all(request).await.unwrap();
+            assert_eq!(
+                read_request(&mut server, Protocol::HttpConnect, 1024)
+                    .await
+                    .unwrap(),
+                None
+            );
+            drop(server);
+            let mut answer = Vec::new();
+            client.read_to_end(&mut answer).await.unwrap();
+            assert_eq!(answer, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let (mut server, mut client) = duplex(4096);
+        client.write_all(&[5, 2, 2, 0]).await.unwrap();
+        client
+            .write_all(&[
+                5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm',
+                1, 187,
+            ])
+            .await
+            .unwrap();
+        let request = read_request(&mut server, Protocol::Socks5, 1024)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.host, "example.com");
+        assert_eq!(request.port, 443);
+        request.deny(server).await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_unsupported() {
+        // authentication with username and password only
+        let (mut server, mut client) = duplex(4096);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        assert_eq!(
+            read_request(&mut server, Protocol::Socks5, 1024)
+                .await
+                .unwrap(),
+            None
+        );
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0xff]);
+
+        // IPv4 address
+        let (mut server, mut client) = duplex(4096);
+        client
+            .write_all(&[5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 1, 187])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_request(&mut server, Protocol::Socks5, 1024)
+                .await
+                .unwrap(),
+            None
+        );
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+}
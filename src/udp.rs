@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use parking_lot::Mutex;
+use tls_client_hello_parser::ClientHello;
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{debug, info};
+
+use crate::{
+    quic::{self, CryptoStream, InitialKeys, PacketType},
+    rate_limit::LimitPermit,
+    state::{ConnectionRef, State},
+};
+
+/// Time a client has to send its complete client hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clients whose client hello is not complete yet
+const MAX_PENDING_HANDSHAKES: usize = 4096;
+
+/// Datagrams buffered per handshake until the backend is known
+const MAX_PENDING_DATAGRAMS: usize = 8;
+
+/// Largest UDP payload
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Routes QUIC connections by the client hello in their Initial packets
+///
+/// Every connection gets its own socket to the backend, like a NAT.
+/// Datagrams are matched to connections by client address
+/// and by the connection IDs of the backend, so clients can migrate.
+/// A new client address is only used after the client answered from it,
+/// so datagrams with a spoofed source can not take over a connection.
+pub struct QuicForwarder {
+    frontend: Arc<str>,
+    socket: Arc<UdpSocket>,
+    state: Arc<State>,
+    idle_timeout: Duration,
+    flows: Mutex<HashMap<SocketAddr, Arc<Flow>>>,
+    connection_ids: Mutex<ConnectionIds>,
+    handshakes: Mutex<HashMap<(SocketAddr, Vec<u8>), Handshake>>,
+}
+
+#[derive(Default)]
+struct ConnectionIds {
+    flows: HashMap<Vec<u8>, Arc<Flow>>,
+    /// Lengths of the known IDs, short headers do not contain the length
+    lengths: BTreeSet<usize>,
+}
+
+/// A forwarded QUIC connection
+struct Flow {
+    client: Mutex<SocketAddr>,
+    /// Address the client might have migrated to
+    migration: Mutex<Option<Migration>>,
+    upstream: UdpSocket,
+    last_active: Mutex<Instant>,
+    connection_ids: Mutex<Vec<Vec<u8>>>,
+    _connection_ref: ConnectionRef,
+    _permits: Vec<LimitPermit>,
+}
+
+/// Unconfirmed new address of a client
+struct Migration {
+    client: SocketAddr,
+    /// Whether a datagram of the backend was sent to the new address
+    answered: bool,
+}
+
+/// Initial packets of a client until its client hello is complete
+struct Handshake {
+    keys: InitialKeys,
+    crypto: CryptoStream,
+    datagrams: Vec<Vec<u8>>,
+    started: Instant,
+}
+
+impl QuicForwarder {
+    pub fn new(frontend: Arc<str>, socket: UdpSocket, state: Arc<State>) -> Arc<Self> {
+        let idle_timeout =
+            Duration::from_secs(state.config.frontends[&*frontend].quic.idle_timeout_secs);
+        Arc::new(Self {
+            frontend,
+            socket: Arc::new(socket),
+            state,
+            idle_timeout,
+            flows: Mutex::default(),
+            connection_ids: Mutex::default(),
+            handshakes: Mutex::default(),
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>) {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_LEN];
+        loop {
+            let (len, client) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!(?err, "failed receiving datagram");
+                    continue;
+                }
+            };
+            if let Err(err) = self.handle_datagram(client, &buffer[..len]).await {
+                debug!(?err, %client, frontend = &*self.frontend, "dropped QUIC datagram");
+            }
+        }
+    }
+
+    async fn handle_datagram(self: &Arc<Self>, client: SocketAddr, datagram: &[u8]) -> Result<()> {
+        let flow = self.flows.lock().get(&client).cloned();
+        if let Some(flow) = flow {
+            return flow.send(datagram).await;
+        }
+        if let Some(flow) = self.flow_by_connection_id(datagram) {
+            // clients can not migrate during the handshake
+            if !quic::is_long_header(datagram) {
+                self.migrate(&flow, client);
+            }
+            return flow.send(datagram).await;
+        }
+        if !quic::is_long_header(datagram) {
+            bail!("short header packet of unknown connection");
+        }
+
+        let client_ip = client.ip().to_canonical();
+        let client_asn = self
+            .state
+            .ip_to_asn_database
+            .lookup_ip(client_ip)
+            .map(|v| v.asn());
+        let frontend_state = &self.state.frontends[&*self.frontend];
+        if !frontend_state.access.is_allowed(client_ip, client_asn) {
+            bail!("denied by frontend access list");
+        }
+
+        let Some(record) = self.collect_client_hello(client, datagram)? else {
+            return Ok(());
+        };
+        let Some(handshake) = self.handshakes.lock().remove(&record.0) else {
+            return Ok(());
+        };
+        let client_hello =
+            ClientHello::try_from(record.1.as_slice()).context("failed parsing client hello")?;
+        let sni = client_hello
+            .sni()
+            .context("client hello does not contain SNI")?;
+        info!(sni, ?client, as_number = client_asn, "got QUIC connection");
+
+        let flow_count = self.flows.lock().len();
+        if let Some(max) = self.state.config.frontends[&*self.frontend].max_connections
+            && flow_count >= max
+        {
+            bail!("frontend is at max connections");
+        }
+        let mut permits =
+            match frontend_state
+                .rate_limits
+                .acquire(Some(client_ip), client_asn, None)
+            {
+                Ok(permits) => permits,
+                Err(limiter) => bail!("rate limited by frontend, key {:?}", limiter.key()),
+            };
+        let pool = self
+            .state
+            .select_pool(sni, client_hello.alpn())
+            .context("domain is not configured")?;
+        if !pool.access.is_allowed(client_ip, client_asn) {
+            bail!("denied by backend access list");
+        }
+        match pool.rate_limits.acquire(Some(client_ip), client_asn, None) {
+            Ok(backend_permits) => permits.extend(backend_permits),
+            Err(limiter) => bail!("rate limited by backend, key {:?}", limiter.key()),
+        }
+        let (target, connection_ref) = pool
+            .datagram_target()
+            .context("backend can not forward QUIC")?;
+
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let upstream = UdpSocket::bind(local).await?;
+        upstream.connect(target).await?;
+        let flow = Arc::new(Flow {
+            client: Mutex::new(client),
+            migration: Mutex::default(),
+            upstream,
+            last_active: Mutex::new(Instant::now()),
+            connection_ids: Mutex::default(),
+            _connection_ref: connection_ref,
+            _permits: permits,
+        });
+        for datagram in &handshake.datagrams {
+            flow.send(datagram).await?;
+        }
+        self.flows.lock().insert(client, Arc::clone(&flow));
+        tokio::spawn(Arc::clone(self).relay(flow));
+        Ok(())
+    }
+
+    /// Buffers the Initial packets of a datagram
+    ///
+    /// Returns the key of the handshake and the client hello as TLS record once it is complete.
+    #[allow(clippy::type_complexity)]
+    fn collect_client_hello(
+        &self,
+        client: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<((SocketAddr, Vec<u8>), Vec<u8>)>> {
+        let mut handshakes = self.handshakes.lock();
+        let mut rest = datagram;
+        let mut key = None;
+        // coalesced packets of other types are forwarded, but not inspected
+        while !rest.is_empty() && quic::is_long_header(rest) {
+            let header = quic::parse_long_header(rest)?;
+            let packet = &rest[..header.len];
+            rest = &rest[header.len..];
+            if header.packet_type != PacketType::Initial {
+                continue;
+            }
+
+            let handshake_key = (client, header.dcid.to_vec());
+            if !handshakes.contains_key(&handshake_key) {
+                if handshakes.len() >= MAX_PENDING_HANDSHAKES {
+                    let now = Instant::now();
+                    handshakes.retain(|_key, handshake| {
+                        now.duration_since(handshake.started) < HANDSHAKE_TIMEOUT
+                    });
+                    if handshakes.len() >= MAX_PENDING_HANDSHAKES {
+                        bail!("too many pending handshakes");
+                    }
+                }
+                handshakes.insert(
+                    handshake_key.clone(),
+                    Handshake {
+                        keys: InitialKeys::client(header.version, header.dcid)?,
+                        crypto: CryptoStream::default(),
+                        datagrams: Vec::new(),
+                        started: Instant::now(),
+                    },
+                );
+            }
+            let handshake = handshakes
+                .get_mut(&handshake_key)
+                .context("handshake disappeared")?;
+            let payload = handshake.keys.decrypt(packet, &header)?;
+            for (offset, data) in quic::crypto_frames(&payload)? {
+                handshake.crypto.insert(offset, data)?;
+            }
+            key = Some(handshake_key);
+        }
+
+        let Some(key) = key else {
+            bail!("datagram contains no Initial packet");
+        };
+        let handshake = handshakes.get_mut(&key).context("handshake disappeared")?;
+        if handshake.datagrams.len() >= MAX_PENDING_DATAGRAMS {
+            handshakes.remove(&key);
+            bail!("client hello spans too many datagrams");
+        }
+        handshake.datagrams.push(datagram.to_vec());
+        Ok(handshake
+            .crypto
+            .client_hello_record()
+            .map(|record| (key, record)))
+    }
+
+    /// Finds the connection of a datagram by the destination connection ID
+    fn flow_by_connection_id(&self, datagram: &[u8]) -> Option<Arc<Flow>> {
+        let connection_ids = self.connection_ids.lock();
+        if quic::is_long_header(datagram) {
+            let header = quic::parse_long_header(datagram).ok()?;
+            return connection_ids.flows.get(header.dcid).cloned();
+        }
+        // short header: one byte of flags, then the ID
+        connection_ids.lengths.iter().find_map(|len| {
+            let id = datagram.get(1..1 + len)?;
+            connection_ids.flows.get(id).cloned()
+        })
+    }
+
+    /// Moves a flow to a new client address once the client answered from it
+    ///
+    /// The first datagram from the new address only starts the migration,
+    /// the next datagram of the backend is also sent to the new address.
+    /// A datagram from the new address after that completes the migration.
+    fn migrate(&self, flow: &Arc<Flow>, client: SocketAddr) {
+        let mut migration = flow.migration.lock();
+        match &*migration {
+            Some(pending) if pending.client == client && pending.answered => {}
+            Some(pending) if pending.client == client => return,
+            _ => {
+                *migration = Some(Migration {
+                    client,
+                    answered: false,
+                });
+                return;
+            }
+        }
+        *migration = None;
+        let previous = std::mem::replace(&mut *flow.client.lock(), client);
+        debug!(%previous, %client, "client migrated");
+        let mut flows = self.flows.lock();
+        flows.remove(&previous);
+        flows.insert(client, Arc::clone(flow));
+    }
+
+    /// Forwards datagrams of the backend to the client until the flow is idle
+    async fn relay(self: Arc<Self>, flow: Arc<Flow>) {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_LEN];
+        loop {
+            match timeout(self.idle_timeout, flow.upstream.recv(&mut buffer)).await {
+                Ok(Ok(len)) => {
+                    let datagram = &buffer[..len];
+                    self.learn_connection_id(&flow, datagram);
+                    let client = *flow.client.lock();
+                    if let Err(err) = self.socket.send_to(datagram, client).await {
+                        debug!(?err, %client, "failed sending datagram to client");
+                    }
+                    let probe = flow
+                        .migration
+                        .lock()
+                        .as_mut()
+                        .filter(|migration| !migration.answered)
+                        .map(|migration| {
+                            migration.answered = true;
+                            migration.client
+                        });
+                    if let Some(probe) = probe
+                        && let Err(err) = self.socket.send_to(datagram, probe).await
+                    {
+                        debug!(?err, client = %probe, "failed sending datagram to new client address");
+                    }
+                    flow.touch();
+                }
+                Ok(Err(err)) => {
+                    debug!(?err, "failed receiving datagram from backend");
+                    break;
+                }
+                Err(_elapsed) => {
+                    if flow.last_active.lock().elapsed() >= self.idle_timeout {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let client = *flow.client.lock();
+        debug!(%client, "QUIC flow expired");
+        self.flows.lock().remove(&client);
+        let ids = std::mem::take(&mut *flow.connection_ids.lock());
+        let mut connection_ids = self.connection_ids.lock();
+        for id in &ids {
+            connection_ids.flows.remove(id);
+        }
+    }
+
+    /// Remembers the connection IDs the backend chose, clients use them after migrating
+    fn learn_connection_id(&self, flow: &Arc<Flow>, datagram: &[u8]) {
+        if !quic::is_long_header(datagram) {
+            return;
+        }
+        let Ok(header) = quic::parse_long_header(datagram) else {
+            return;
+        };
+        if header.scid.is_empty() {
+            return;
+        }
+        let mut ids = flow.connection_ids.lock();
+        if ids.iter().any(|id| id == header.scid) {
+            return;
+        }
+        ids.push(header.scid.to_vec());
+        let mut connection_ids = self.connection_ids.lock();
+        connection_ids.lengths.insert(header.scid.len());
+        connection_ids
+            .flows
+            .insert(header.scid.to_vec(), Arc::clone(flow));
+    }
+}
+
+impl Flow {
+    async fn send(&self, datagram: &[u8]) -> Result<()> {
+        self.upstream
+            .send(datagram)
+            .await
+            .context("failed sending datagram to backend")?;
+        self.touch();
+        Ok(())
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+}
@@ -0,0 +1,53 @@
+/// Severity of a TLS alert
+///
+/// TLS 1.3 ignores the level and treats every alert except `close_notify` as fatal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AlertLevel {
+    Warning = 1,
+    Fatal = 2,
+}
+
+/// Alert descriptions as defined in RFC 8446, section 6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AlertDescription {
+    CloseNotify = 0,
+    UnexpectedMessage = 10,
+    HandshakeFailure = 40,
+    AccessDenied = 49,
+    DecodeError = 50,
+    ProtocolVersion = 70,
+    InternalError = 80,
+    UnrecognizedName = 112,
+    NoApplicationProtocol = 120,
+}
+
+/// Length of an encoded alert record
+pub const ALERT_RECORD_LEN: usize = 7;
+
+/// Encodes a plaintext alert record, as it is sent before the handshake is completed
+///
+/// # Example
+/// ```
+/// # use tls_client_hello_parser::{AlertDescription, AlertLevel, encode_alert};
+/// let record = encode_alert(AlertLevel::Fatal, AlertDescription::UnrecognizedName);
+/// assert_eq!(record, [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 112]);
+/// ```
+pub const fn encode_alert(
+    level: AlertLevel,
+    description: AlertDescription,
+) -> [u8; ALERT_RECORD_LEN] {
+    [
+        // content type alert
+        0x15,
+        // legacy record version TLS 1.2
+        0x03,
+        0x03,
+        // length
+        0x00,
+        0x02,
+        level as u8,
+        description as u8,
+    ]
+}
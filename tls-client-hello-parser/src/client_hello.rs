@@ -14,7 +14,10 @@ use nom7::{
     },
 };
 
-use crate::TlsParseError;
+use crate::{
+    TlsParseError,
+    ech::{EXTENSION_ENCRYPTED_CLIENT_HELLO, EncryptedClientHello, parse_encrypted_client_hello},
+};
 
 /// Extracted information needed
 /// to route and fingerprint TLS sessions
@@ -26,6 +29,7 @@ pub struct ClientHello<'a> {
     extensions: Vec<u16, 128>,
     signature_algorithms: Vec<u16, 30>,
     alpn: Vec<&'a [u8], 4>,
+    ech: Option<EncryptedClientHello<'a>>,
 }
 
 impl<'a> ClientHello<'a> {
@@ -36,9 +40,8 @@ impl<'a> ClientHello<'a> {
 
     /// Server Name Identification extracted from the packet
     ///
-    /// In case the target is an IP address _or_ ECH is used, this is None
-    ///
-    /// **TODO** verify above statement
+    /// In case the target is an IP address, this is None.
+    /// With ECH, this is the public name of the outer client hello.
     pub fn sni(&self) -> Option<&str> {
         self.sni
     }
@@ -62,6 +65,11 @@ impl<'a> ClientHello<'a> {
     pub fn alpn(&self) -> &[&[u8]] {
         &self.alpn
     }
+
+    /// Encrypted Client Hello extension, if the client sent one
+    pub fn ech(&self) -> Option<&EncryptedClientHello<'a>> {
+        self.ech.as_ref()
+    }
 }
 
 impl<'a> TryFrom<&'a [u8]> for ClientHello<'a> {
@@ -156,6 +164,7 @@ fn extract_metadata_from_tls_handshake_client_hello(i: &[u8]) -> IResult<&[u8],
         extensions: Vec::new(),
         signature_algorithms: Vec::new(),
         alpn: Vec::new(),
+        ech: None,
     };
 
     if let Some(mut ext) = ext {
@@ -240,6 +249,10 @@ fn extract_extension_metadata<'a>(
                 client_hello.tls_version = v;
             }
         }
+        EXTENSION_ENCRYPTED_CLIENT_HELLO => {
+            let (_i, ech) = complete(parse_encrypted_client_hello)(extension_data).ok()?;
+            client_hello.ech = Some(ech);
+        }
         // ESNI
         0xffce => {
             //warn!("got ESNI :(");
@@ -396,8 +409,9 @@ mod tests {
     use pretty_assertions::assert_eq;
 
     use crate::{
-        ClientHello,
+        ClientHello, EncryptedClientHello, RawClientHello, TlsParseError,
         ja4::{Ja4Fingerprint, u16_slice_to_hex},
+        parse_outer_extensions,
     };
 
     #[test]
@@ -538,6 +552,7 @@ mod tests {
                 .into_iter()
                 .collect(),
             //ja4: "t13d3012h2_1d37bd780c83_882d495ac381".try_into().unwrap(),
+            ech: None,
         };
 
         assert_eq!(
@@ -581,6 +596,73 @@ mod tests {
             Ja4Fingerprint::calculate(&output).as_ref(),
             "t13i1716h2_5b57614c22b0_3cbfd9057e0d"
         );
+
+        let Some(&EncryptedClientHello::Outer {
+            kdf_id,
+            aead_id,
+            config_id,
+            enc,
+            payload,
+        }) = output.ech()
+        else {
+            panic!("client hello has an outer ECH extension");
+        };
+        assert_eq!(
+            output.ech().and_then(EncryptedClientHello::config_id),
+            Some(197)
+        );
+        // HKDF-SHA256 and AES-128-GCM
+        assert_eq!((kdf_id, aead_id, config_id), (0x0001, 0x0001, 197));
+        assert_eq!(
+            enc,
+            [
+                0xbb, 0x94, 0xb8, 0xda, 0x1d, 0x94, 0x41, 0x35, 0x23, 0xf7, 0x4f, 0x85, 0x15, 0x16,
+                0x98, 0xf9, 0x93, 0x28, 0xfe, 0x7e, 0x8b, 0x4d, 0x49, 0x26, 0x61, 0x28, 0xff, 0xf6,
+                0x3d, 0x0b, 0xa0, 0x5c,
+            ]
+        );
+        assert_eq!(payload.len(), 239);
+        assert_eq!(
+            payload[..8],
+            [0x99, 0x8b, 0x2f, 0xb3, 0x56, 0x83, 0x23, 0x79]
+        );
+    }
+
+    #[test]
+    fn test_raw_firefox_ech() {
+        let header = parse_hexdump(include_str!("./testcases/ip-firefox-ech.hex"));
+        let output = ClientHello::try_from(header.as_slice()).unwrap();
+        let raw = RawClientHello::try_from(header.as_slice()).unwrap();
+
+        assert_eq!(raw.legacy_version, 0x0303);
+        assert_eq!(raw.session_id.len(), 32);
+        assert_eq!(raw.cipher_suites.len(), 2 * output.cipher_suites().len());
+        assert_eq!(raw.extensions.len(), output.extensions().len());
+        for extension in &raw.extensions {
+            let end = extension.offset + extension.data.len();
+            assert_eq!(&raw.body[extension.offset..end], extension.data);
+        }
+        let ech = raw.ech().unwrap();
+        assert_eq!(
+            EncryptedClientHello::try_from(ech.data).as_ref(),
+            Ok(output.ech().unwrap())
+        );
+
+        assert_eq!(
+            RawClientHello::try_from(&header[..100]),
+            Err(TlsParseError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn test_outer_extensions() {
+        assert_eq!(
+            parse_outer_extensions(&[4, 0x00, 0x10, 0x00, 0x2b]).unwrap(),
+            [16, 43]
+        );
+        assert!(parse_outer_extensions(&[0]).is_err());
+        assert!(parse_outer_extensions(&[3, 0x00, 0x10, 0x00]).is_err());
+        assert!(parse_outer_extensions(&[2, 0x00, 0x10, 0x00]).is_err());
     }
 
     #[test]
@@ -638,6 +720,7 @@ mod tests {
         let output = ClientHello::try_from(header.as_slice())
             .expect("example data contains correct client hello");
 
+        assert_eq!(output.ech(), None);
         assert_eq!(output.tls_version(), 0x0304);
         assert_eq!(output.sni(), Some("rappet.xyz"));
         assert_eq!(output.alpn(), &[&b"h2"[..], &b"http/1.1"[..]]);
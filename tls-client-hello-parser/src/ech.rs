@@ -0,0 +1,132 @@
+use heapless::Vec;
+use nom7::{
+    IResult,
+    combinator::{all_consuming, complete},
+    error::{ErrorKind, make_error},
+    multi::length_data,
+    number::complete::{be_u8, be_u16},
+};
+
+use crate::TlsParseError;
+
+/// Type of the `encrypted_client_hello` extension
+pub const EXTENSION_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+
+/// Type of the `ech_outer_extensions` extension of encoded inner client hellos
+pub const EXTENSION_ECH_OUTER_EXTENSIONS: u16 = 0xfd00;
+
+/// Content of the `encrypted_client_hello` extension
+///
+/// Clients without an ECH config send a random outer extension (GREASE ECH),
+/// which looks like a real one without the private key.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EncryptedClientHello<'a> {
+    /// Outer client hello, the inner client hello is encrypted in the payload
+    Outer {
+        /// HPKE KDF of the cipher suite
+        kdf_id: u16,
+        /// HPKE AEAD of the cipher suite
+        aead_id: u16,
+        /// Identifier of the ECH config the client used
+        config_id: u8,
+        /// Encapsulated HPKE key, empty after a hello retry request
+        enc: &'a [u8],
+        /// Encrypted inner client hello
+        payload: &'a [u8],
+    },
+    /// Inner client hello, only seen after decrypting the payload of the outer one
+    Inner,
+}
+
+impl EncryptedClientHello<'_> {
+    /// Whether this is the extension of an outer client hello
+    pub const fn is_outer(&self) -> bool {
+        matches!(self, Self::Outer { .. })
+    }
+
+    /// Identifier of the ECH config, `None` for inner client hellos
+    pub const fn config_id(&self) -> Option<u8> {
+        match self {
+            Self::Outer { config_id, .. } => Some(*config_id),
+            Self::Inner => None,
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for EncryptedClientHello<'a> {
+    type Error = TlsParseError;
+
+    /// Parses the data of the extension
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        match all_consuming(parse_encrypted_client_hello)(value) {
+            Ok((_, ech)) => Ok(ech),
+            Err(_) => Err(TlsParseError::ParseOuterPacket),
+        }
+    }
+}
+
+/// Types of the extensions an encoded inner client hello copies from the outer one
+///
+/// # Errors
+/// If the data of the `ech_outer_extensions` extension is malformed or lists no extension
+pub fn parse_outer_extensions(data: &[u8]) -> Result<Vec<u16, 127>, TlsParseError> {
+    match all_consuming(outer_extensions)(data) {
+        Ok((_, extension_types)) => Ok(extension_types),
+        Err(_) => Err(TlsParseError::ParseOuterPacket),
+    }
+}
+
+// ExtensionType OuterExtensions<2..254>;
+fn outer_extensions(i: &[u8]) -> IResult<&[u8], Vec<u16, 127>> {
+    let (i, extension_types) = complete(length_data(be_u8))(i)?;
+    if extension_types.is_empty() || extension_types.len() % 2 != 0 {
+        return Err(nom7::Err::Error(make_error(i, ErrorKind::LengthValue)));
+    }
+    let extension_types = extension_types
+        .chunks_exact(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+        .collect();
+    Ok((i, extension_types))
+}
+
+// enum { outer(0), inner(1) } ECHClientHelloType;
+//
+// struct {
+//    ECHClientHelloType type;
+//    select (ECHClientHello.type) {
+//        case outer:
+//            HpkeSymmetricCipherSuite cipher_suite;
+//            uint8 config_id;
+//            opaque enc<0..2^16-1>;
+//            opaque payload<1..2^16-1>;
+//        case inner:
+//            Empty;
+//    };
+// } ECHClientHello;
+pub(crate) fn parse_encrypted_client_hello(i: &[u8]) -> IResult<&[u8], EncryptedClientHello<'_>> {
+    let (i, ech_type) = be_u8(i)?;
+    match ech_type {
+        0 => {
+            let (i, kdf_id) = be_u16(i)?;
+            let (i, aead_id) = be_u16(i)?;
+            let (i, config_id) = be_u8(i)?;
+            let (i, enc) = complete(length_data(be_u16))(i)?;
+            let (i, payload) = complete(length_data(be_u16))(i)?;
+            if payload.is_empty() {
+                return Err(nom7::Err::Error(make_error(i, ErrorKind::Verify)));
+            }
+            Ok((
+                i,
+                EncryptedClientHello::Outer {
+                    kdf_id,
+                    aead_id,
+                    config_id,
+                    enc,
+                    payload,
+                },
+            ))
+        }
+        1 => Ok((i, EncryptedClientHello::Inner)),
+        _ => Err(nom7::Err::Error(make_error(i, ErrorKind::Switch))),
+    }
+}
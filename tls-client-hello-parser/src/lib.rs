@@ -1,11 +1,20 @@
 #![no_std]
 extern crate alloc;
 
+mod alert;
 mod client_hello;
+mod ech;
 mod ja4;
+mod raw;
 
+pub use alert::{ALERT_RECORD_LEN, AlertDescription, AlertLevel, encode_alert};
 pub use client_hello::ClientHello;
+pub use ech::{
+    EXTENSION_ECH_OUTER_EXTENSIONS, EXTENSION_ENCRYPTED_CLIENT_HELLO, EncryptedClientHello,
+    parse_outer_extensions,
+};
 pub use ja4::Ja4Fingerprint;
+pub use raw::{RawClientHello, RawExtension};
 use thiserror::Error;
 
 #[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
@@ -0,0 +1,135 @@
+use heapless::Vec;
+use nom7::{
+    IResult,
+    bytes::complete::take,
+    combinator::complete,
+    error::{ErrorKind, make_error},
+    multi::length_data,
+    number::{
+        complete::{be_u8, be_u16, be_u24},
+        streaming,
+    },
+};
+
+use crate::{
+    TlsParseError, client_hello::parse_tls_extension_header, ech::EXTENSION_ENCRYPTED_CLIENT_HELLO,
+};
+
+/// Client hello with its fields as sent
+///
+/// Unlike [`ClientHello`](crate::ClientHello), nothing is extracted or left out,
+/// this is needed to reconstruct the inner client hello of Encrypted Client Hello.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RawClientHello<'a> {
+    /// Body of the client hello message, without the handshake header
+    pub body: &'a [u8],
+    pub legacy_version: u16,
+    pub random: &'a [u8],
+    pub session_id: &'a [u8],
+    /// Cipher suites, two bytes each, including grease
+    pub cipher_suites: &'a [u8],
+    pub compression_methods: &'a [u8],
+    /// Extensions in the order of the client hello, including grease
+    pub extensions: Vec<RawExtension<'a>, 128>,
+}
+
+/// An extension of a [`RawClientHello`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RawExtension<'a> {
+    pub extension_type: u16,
+    pub data: &'a [u8],
+    /// Start of the data in the body of the client hello
+    pub offset: usize,
+}
+
+impl<'a> RawClientHello<'a> {
+    /// Parses the body of a client hello message
+    ///
+    /// Returns the client hello and the bytes after its extensions,
+    /// like the padding of an encoded inner client hello.
+    ///
+    /// # Errors
+    /// If the body is malformed
+    pub fn parse_body(body: &'a [u8]) -> Result<(Self, &'a [u8]), TlsParseError> {
+        match parse_raw_client_hello(body) {
+            Ok((rest, client_hello)) => Ok((client_hello, rest)),
+            Err(_) => Err(TlsParseError::ParseOuterPacket),
+        }
+    }
+
+    /// First extension of a type
+    pub fn extension(&self, extension_type: u16) -> Option<&RawExtension<'a>> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.extension_type == extension_type)
+    }
+
+    /// The `encrypted_client_hello` extension, if the client sent one
+    pub fn ech(&self) -> Option<&RawExtension<'a>> {
+        self.extension(EXTENSION_ENCRYPTED_CLIENT_HELLO)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for RawClientHello<'a> {
+    type Error = TlsParseError;
+
+    /// Parses a record with a client hello, bytes after the extensions are ignored
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        let (_rest, body) = match client_hello_body(value) {
+            Ok(parsed) => parsed,
+            Err(nom7::Err::Incomplete(_)) => return Err(TlsParseError::Incomplete),
+            Err(_) => return Err(TlsParseError::ParseOuterPacket),
+        };
+        Ok(Self::parse_body(body)?.0)
+    }
+}
+
+/// Body of the client hello message in a handshake record
+fn client_hello_body(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, record_type) = streaming::be_u8(i)?;
+    let (i, _record_version) = streaming::be_u16(i)?;
+    let (i, record) = length_data(streaming::be_u16)(i)?;
+    let (message, message_type) = be_u8(record)?;
+    // handshake record with a client hello
+    if record_type != 0x16 || message_type != 0x01 {
+        return Err(nom7::Err::Error(make_error(i, ErrorKind::Switch)));
+    }
+    let (_, body) = complete(length_data(be_u24))(message)?;
+    Ok((i, body))
+}
+
+fn parse_raw_client_hello(body: &[u8]) -> IResult<&[u8], RawClientHello<'_>> {
+    let (i, legacy_version) = be_u16(body)?;
+    let (i, random) = take(32usize)(i)?;
+    let (i, session_id) = complete(length_data(be_u8))(i)?;
+    let (i, cipher_suites) = complete(length_data(be_u16))(i)?;
+    let (i, compression_methods) = complete(length_data(be_u8))(i)?;
+    let (rest, mut data) = complete(length_data(be_u16))(i)?;
+
+    let mut extensions = Vec::new();
+    while !data.is_empty() {
+        let (next, (extension_type, extension_data)) = complete(parse_tls_extension_header)(data)?;
+        let offset = body.len() - rest.len() - next.len() - extension_data.len();
+        extensions
+            .push(RawExtension {
+                extension_type,
+                data: extension_data,
+                offset,
+            })
+            .map_err(|_| nom7::Err::Error(make_error(data, ErrorKind::TooLarge)))?;
+        data = next;
+    }
+
+    Ok((
+        rest,
+        RawClientHello {
+            body,
+            legacy_version,
+            random,
+            session_id,
+            cipher_suites,
+            compression_methods,
+            extensions,
+        },
+    ))
+}